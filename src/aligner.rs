@@ -0,0 +1,241 @@
+//! Short-read aligners behind one interface, chosen per stage on the command line.
+//!
+//! Each backend writes SAM to stdout. The reference given to an aligner must be
+//! indexed for that tool: a bwa-indexed FASTA, a bowtie2 index prefix, or a FASTA
+//! (or `.mmi`) for minimap2.
+
+use crate::bwa::BwaMem;
+use crate::cmd::{Cmd, Redirect};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The FASTQ files of one alignment run.
+#[derive(Debug, Clone)]
+pub enum Reads {
+    Single(PathBuf),
+    Paired(PathBuf, PathBuf),
+    /// Both mates in one file, each first mate followed by its second.
+    Interleaved(PathBuf),
+}
+
+impl Reads {
+    pub fn files(&self) -> Vec<&Path> {
+        match self {
+            Reads::Single(fq) | Reads::Interleaved(fq) => vec![fq],
+            Reads::Paired(fq1, fq2) => vec![fq1, fq2],
+        }
+    }
+}
+
+pub trait Aligner {
+    /// The command aligning `reads` to `reference`, writing SAM to stdout.
+    fn command(&self, reference: &str, reads: &Reads) -> Cmd;
+
+    /// Aligns `reads` to `reference` and writes the SAM output to `output`, with
+    /// the aligner's log in `<output>.log`.
+    fn align_to_file(&self, reference: &str, reads: &Reads, output: &Path) -> Result<()> {
+        let mut log = output.as_os_str().to_os_string();
+        log.push(".log");
+        self.command(reference, reads)
+            .stdout(Redirect::File(output.to_path_buf()))
+            .stderr(Redirect::File(log.into()))
+            .run()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlignerKind {
+    Bwa,
+    Bowtie2,
+    Minimap2,
+}
+
+impl AlignerKind {
+    /// Aligner for the human reference with the tool's default scoring.
+    pub fn human(self, threads: u32) -> Box<dyn Aligner> {
+        match self {
+            AlignerKind::Bwa => Box::new(BwaMem::new(threads)),
+            AlignerKind::Bowtie2 => Box::new(Bowtie2::new(threads)),
+            AlignerKind::Minimap2 => Box::new(Minimap2::new(threads)),
+        }
+    }
+
+    /// Aligner for the TE library, reporting hits to every matching family.
+    /// `min_score` is the minimum alignment score to report.
+    pub fn te_library(self, threads: u32, min_score: u32) -> Box<dyn Aligner> {
+        match self {
+            AlignerKind::Bwa => Box::new(BwaMem::te_library(threads, min_score)),
+            AlignerKind::Bowtie2 => Box::new(Bowtie2 {
+                max_hits: Some(TE_MAX_HITS),
+                min_score: Some(min_score),
+                ..Bowtie2::new(threads)
+            }),
+            AlignerKind::Minimap2 => Box::new(Minimap2 {
+                max_secondary: Some(TE_MAX_HITS),
+                min_score: Some(min_score),
+                ..Minimap2::new(threads)
+            }),
+        }
+    }
+}
+
+/// Hits kept per read against the TE library by aligners that need a limit.
+const TE_MAX_HITS: u32 = 100;
+
+/// `bowtie2` in local mode, so reads spanning a breakpoint are soft-clipped.
+#[derive(Debug, Clone)]
+pub struct Bowtie2 {
+    pub program: String,
+    /// `-p`
+    pub threads: u32,
+    /// `-k`: report up to this many alignments per read.
+    pub max_hits: Option<u32>,
+    /// `--score-min C,<n>`: minimum local alignment score.
+    pub min_score: Option<u32>,
+}
+
+impl Bowtie2 {
+    pub fn new(threads: u32) -> Self {
+        Bowtie2 {
+            program: "bowtie2".to_string(),
+            threads,
+            max_hits: None,
+            min_score: None,
+        }
+    }
+}
+
+impl Aligner for Bowtie2 {
+    fn command(&self, reference: &str, reads: &Reads) -> Cmd {
+        let mut cmd = Cmd::new(&self.program)
+            .arg("--local")
+            .args(["-p", &self.threads.to_string()]);
+        if let Some(k) = self.max_hits {
+            cmd = cmd.args(["-k", &k.to_string()]);
+        }
+        if let Some(score) = self.min_score {
+            cmd = cmd.args(["--score-min", &format!("C,{}", score)]);
+        }
+        cmd = cmd.args(["-x", reference]);
+        match reads {
+            Reads::Single(fq) => cmd.arg("-U").arg(fq),
+            Reads::Paired(fq1, fq2) => cmd.arg("-1").arg(fq1).arg("-2").arg(fq2),
+            Reads::Interleaved(fq) => cmd.arg("--interleaved").arg(fq),
+        }
+    }
+}
+
+/// `minimap2` with the short-read preset.
+#[derive(Debug, Clone)]
+pub struct Minimap2 {
+    pub program: String,
+    /// `-t`
+    pub threads: u32,
+    /// `-N`: retain up to this many secondary alignments.
+    pub max_secondary: Option<u32>,
+    /// `-s`: minimum peak alignment score.
+    pub min_score: Option<u32>,
+}
+
+impl Minimap2 {
+    pub fn new(threads: u32) -> Self {
+        Minimap2 {
+            program: "minimap2".to_string(),
+            threads,
+            max_secondary: None,
+            min_score: None,
+        }
+    }
+}
+
+impl Aligner for Minimap2 {
+    fn command(&self, reference: &str, reads: &Reads) -> Cmd {
+        let mut cmd = Cmd::new(&self.program)
+            .args(["-a", "-x", "sr"])
+            .args(["-t", &self.threads.to_string()]);
+        if let Some(n) = self.max_secondary {
+            cmd = cmd.args(["--secondary=yes", "-N", &n.to_string()]);
+        }
+        if let Some(score) = self.min_score {
+            cmd = cmd.args(["-s", &score.to_string()]);
+        }
+        if let Reads::Interleaved(_) = reads {
+            // pair consecutive reads with the same name
+            cmd = cmd.arg("--frag=yes");
+        }
+        cmd.arg(reference).args(reads.files())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_line(aligner: &dyn Aligner, reads: &Reads) -> String {
+        aligner.command("ref", reads).to_string()
+    }
+
+    #[test]
+    fn bwa_pairs_interleaved_reads_with_p() {
+        let bwa = AlignerKind::Bwa.human(4);
+        assert_eq!(
+            command_line(bwa.as_ref(), &Reads::Interleaved("s.fq".into())),
+            "bwa mem -t 4 -k 19 -r 1.5 -c 500 -m 50 -T 30 -h 5 -p ref s.fq"
+        );
+        assert_eq!(
+            command_line(bwa.as_ref(), &Reads::Paired("1.fq".into(), "2.fq".into())),
+            "bwa mem -t 4 -k 19 -r 1.5 -c 500 -m 50 -T 30 -h 5 ref 1.fq 2.fq"
+        );
+        let te = AlignerKind::Bwa.te_library(2, 20);
+        assert_eq!(
+            command_line(te.as_ref(), &Reads::Single("u.fq".into())),
+            "bwa mem -t 2 -k 19 -r 1.5 -c 100000 -m 50 -T 20 -h 10000 -a -Y -M ref u.fq"
+        );
+    }
+
+    #[test]
+    fn bowtie2_reads_arguments() {
+        let bowtie2 = AlignerKind::Bowtie2.human(4);
+        assert_eq!(
+            command_line(bowtie2.as_ref(), &Reads::Interleaved("s.fq".into())),
+            "bowtie2 --local -p 4 -x ref --interleaved s.fq"
+        );
+        assert_eq!(
+            command_line(
+                bowtie2.as_ref(),
+                &Reads::Paired("1.fq".into(), "2.fq".into())
+            ),
+            "bowtie2 --local -p 4 -x ref -1 1.fq -2 2.fq"
+        );
+        let te = AlignerKind::Bowtie2.te_library(2, 20);
+        assert_eq!(
+            command_line(te.as_ref(), &Reads::Single("u.fq".into())),
+            "bowtie2 --local -p 2 -k 100 --score-min C,20 -x ref -U u.fq"
+        );
+    }
+
+    #[test]
+    fn minimap2_pairs_interleaved_reads_with_frag() {
+        let minimap2 = AlignerKind::Minimap2.human(4);
+        assert_eq!(
+            command_line(minimap2.as_ref(), &Reads::Interleaved("s.fq".into())),
+            "minimap2 -a -x sr -t 4 --frag=yes ref s.fq"
+        );
+        assert_eq!(
+            command_line(
+                minimap2.as_ref(),
+                &Reads::Paired("1.fq".into(), "2.fq".into())
+            ),
+            "minimap2 -a -x sr -t 4 ref 1.fq 2.fq"
+        );
+        let te = AlignerKind::Minimap2.te_library(2, 20);
+        assert_eq!(
+            command_line(te.as_ref(), &Reads::Single("u.fq".into())),
+            "minimap2 -a -x sr -t 2 --secondary=yes -N 100 -s 20 ref u.fq"
+        );
+    }
+}
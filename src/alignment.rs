@@ -0,0 +1,156 @@
+//! Reads alignment records from BAM, SAM or CRAM files behind one interface.
+
+use crate::bam::{BamReader, Header, Record};
+use crate::cmd::{Cmd, Running};
+use crate::input::InputFormat;
+use crate::sam::SamReader;
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::process::ChildStdout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentFormat {
+    Bam,
+    Sam,
+    Cram,
+}
+
+pub enum AlignmentReader {
+    Bam(BamReader<BufReader<File>>),
+    Sam(SamReader<BufReader<File>>),
+    /// CRAM is decoded by `samtools view`.
+    Cram(SamPipe),
+}
+
+impl AlignmentReader {
+    /// Opens an alignment file of any supported format. `reference` is the FASTA
+    /// used to decode CRAM and is ignored for BAM and SAM.
+    pub fn open<P: AsRef<Path>>(path: P, reference: Option<&str>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = match InputFormat::detect(path)
+            .with_context(|| format!("could not open {}", path.display()))?
+        {
+            InputFormat::Alignment(format) => format,
+            InputFormat::Fastq => {
+                return Err(anyhow!(
+                    "{} is FASTQ, not an alignment file",
+                    path.display()
+                ))
+            }
+        };
+        let reader = match format {
+            AlignmentFormat::Bam => AlignmentReader::Bam(
+                BamReader::from_path(path)
+                    .with_context(|| format!("could not read BAM {}", path.display()))?,
+            ),
+            AlignmentFormat::Sam => AlignmentReader::Sam(
+                SamReader::new(BufReader::new(File::open(path)?))
+                    .with_context(|| format!("could not read SAM {}", path.display()))?,
+            ),
+            AlignmentFormat::Cram => {
+                let mut cmd = Cmd::new("samtools").args(["view", "-h"]);
+                if let Some(reference) = reference {
+                    cmd = cmd.args(["-T", reference]);
+                }
+                AlignmentReader::Cram(
+                    SamPipe::spawn(&cmd.arg(path)).context("CRAM input needs samtools on PATH")?,
+                )
+            }
+        };
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        match self {
+            AlignmentReader::Bam(reader) => reader.header(),
+            AlignmentReader::Sam(reader) => reader.header(),
+            AlignmentReader::Cram(reader) => reader.header(),
+        }
+    }
+}
+
+impl Iterator for AlignmentReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            AlignmentReader::Bam(reader) => reader.next(),
+            AlignmentReader::Sam(reader) => reader.next(),
+            AlignmentReader::Cram(reader) => reader.next(),
+        }
+    }
+}
+
+/// SAM records read from the stdout of a running command such as an aligner or
+/// `samtools view`. The command is waited for at the end of the stream, and a
+/// failure is returned as the last item.
+pub struct SamPipe {
+    reader: SamReader<BufReader<ChildStdout>>,
+    process: Option<Running>,
+}
+
+impl SamPipe {
+    pub fn spawn(cmd: &Cmd) -> Result<Self> {
+        let (stdout, process) = cmd.spawn_reader()?;
+        let reader = match SamReader::new(BufReader::new(stdout)) {
+            Ok(reader) => reader,
+            Err(err) => {
+                process.wait()?;
+                return Err(err.into());
+            }
+        };
+        Ok(SamPipe {
+            reader,
+            process: Some(process),
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        self.reader.header()
+    }
+}
+
+impl Iterator for SamPipe {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.next() {
+            None => self
+                .process
+                .take()?
+                .wait()
+                .err()
+                .map(|e| Err(io::Error::other(e))),
+            record => record,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastq::{FastqRecord, FastqWriter};
+    use crate::testdir::TempDir;
+
+    #[test]
+    fn gzip_fastq_is_not_read_as_bam() {
+        let dir = TempDir::new("alignment");
+        let path = dir.join("reads.fq.gz");
+        let mut writer = FastqWriter::create(&path).unwrap();
+        writer
+            .write_record(&FastqRecord {
+                name: "r1".to_string(),
+                seq: b"ACGT".to_vec(),
+                qual: b"IIII".to_vec(),
+            })
+            .unwrap();
+        writer.finish().unwrap();
+        let err = AlignmentReader::open(&path, None).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("{} is FASTQ, not an alignment file", path.display())
+        );
+    }
+}
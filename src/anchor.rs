@@ -0,0 +1,203 @@
+//! Selection of uniquely mapped anchor reads from their aligner scores.
+//!
+//! Anchors are written to `<id>.type`, one tab-separated line per read with the
+//! columns `read_id mate AS XS cigar chrom position strand`:
+//! - `mate` is `R` for the first read of a pair (or an unpaired read) and `L` for
+//!   the second, as in ERVcaller;
+//! - `XS` is `NA` when the aligner reported no other hit;
+//! - `position` is the 1-based alignment start and `strand` is `+` or `-`.
+
+use crate::bam::{flags, Header, Record};
+use crate::breakpoint::Strand;
+use anyhow::Result;
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone)]
+pub struct AnchorParams {
+    /// Minimum `AS` for a read to anchor an insertion.
+    pub min_alignment_score: i32,
+    /// Minimum `AS / XS` for reads with a suboptimal hit.
+    pub min_as_xs_ratio: f64,
+}
+
+impl AnchorParams {
+    /// Whether an alignment scoring `a_s`, with best other hit `xs`, is reliable
+    /// enough to anchor an insertion. A missing `xs` means the aligner reported
+    /// no other hit, so the read is uniquely mapped and only `a_s` is checked.
+    pub fn accepts(&self, a_s: i32, xs: Option<i32>) -> bool {
+        if a_s < self.min_alignment_score {
+            return false;
+        }
+        match xs {
+            None => true,
+            Some(xs) => f64::from(a_s) >= self.min_as_xs_ratio * f64::from(xs),
+        }
+    }
+
+    /// The anchor described by `record`, if it is a mapped, scored alignment
+    /// that passes [`AnchorParams::accepts`].
+    pub fn classify(&self, header: &Header, record: &Record) -> Option<Anchor> {
+        if record.is_unmapped() {
+            return None;
+        }
+        let alignment_score = record.alignment_score()?;
+        let suboptimal_score = record.suboptimal_score();
+        if !self.accepts(alignment_score, suboptimal_score) {
+            return None;
+        }
+        Some(Anchor {
+            read_id: record.qname.clone(),
+            mate: if record.has_flag(flags::SECOND_IN_PAIR) {
+                Mate::Second
+            } else {
+                Mate::First
+            },
+            alignment_score,
+            suboptimal_score,
+            cigar: record.cigar.to_string(),
+            chrom: header.reference_name(record.ref_id).to_string(),
+            position: (record.pos + 1) as u32,
+            strand: if record.is_reverse() {
+                Strand::Reverse
+            } else {
+                Strand::Forward
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mate {
+    First,
+    Second,
+}
+
+impl Mate {
+    /// The other read of the pair.
+    pub fn opposite(self) -> Mate {
+        match self {
+            Mate::First => Mate::Second,
+            Mate::Second => Mate::First,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anchor {
+    pub read_id: String,
+    pub mate: Mate,
+    pub alignment_score: i32,
+    pub suboptimal_score: Option<i32>,
+    pub cigar: String,
+    pub chrom: String,
+    pub position: u32,
+    pub strand: Strand,
+}
+
+impl fmt::Display for Mate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mate::First => "R",
+            Mate::Second => "L",
+        })
+    }
+}
+
+impl fmt::Display for Anchor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t",
+            self.read_id, self.mate, self.alignment_score
+        )?;
+        match self.suboptimal_score {
+            Some(xs) => write!(f, "{}", xs)?,
+            None => f.write_str("NA")?,
+        }
+        write!(
+            f,
+            "\t{}\t{}\t{}\t{}",
+            self.cigar, self.chrom, self.position, self.strand
+        )
+    }
+}
+
+/// Streams `records` and writes one `.type` line per accepted anchor, returning
+/// the anchors written.
+pub fn write_anchors<I, W>(
+    records: I,
+    header: &Header,
+    params: &AnchorParams,
+    mut out: W,
+) -> Result<Vec<Anchor>>
+where
+    I: IntoIterator<Item = io::Result<Record>>,
+    W: Write,
+{
+    let mut anchors = Vec::new();
+    for record in records {
+        if let Some(anchor) = params.classify(header, &record?) {
+            writeln!(out, "{}", anchor)?;
+            anchors.push(anchor);
+        }
+    }
+    out.flush()?;
+    Ok(anchors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::SamReader;
+
+    const PARAMS: AnchorParams = AnchorParams {
+        min_alignment_score: 30,
+        min_as_xs_ratio: 2.0,
+    };
+
+    /// The `.type` lines written for the alignments in `body`.
+    fn type_lines(body: &str) -> Vec<String> {
+        let sam = format!("@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100000\n{}", body);
+        let reader = SamReader::new(sam.as_bytes()).unwrap();
+        let header = reader.header().clone();
+        let mut out = Vec::new();
+        write_anchors(reader, &header, &PARAMS, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn read_without_xs_is_unique() {
+        let lines = type_lines("r1\t65\tchr1\t1000\t60\t100M\t*\t0\t0\t*\t*\tAS:i:100\n");
+        assert_eq!(lines, ["r1\tR\t100\tNA\t100M\tchr1\t1000\t+"]);
+    }
+
+    #[test]
+    fn xs_is_checked_against_the_ratio() {
+        let lines = type_lines(
+            "r1\t65\tchr1\t1000\t60\t100M\t*\t0\t0\t*\t*\tAS:i:100\tXS:i:40\n\
+             r2\t65\tchr1\t2000\t3\t100M\t*\t0\t0\t*\t*\tAS:i:100\tXS:i:60\n",
+        );
+        assert_eq!(lines, ["r1\tR\t100\t40\t100M\tchr1\t1000\t+"]);
+    }
+
+    #[test]
+    fn low_alignment_score_is_rejected() {
+        assert!(type_lines("r1\t65\tchr1\t1000\t60\t100M\t*\t0\t0\t*\t*\tAS:i:29\n").is_empty());
+    }
+
+    #[test]
+    fn unmapped_read_is_rejected() {
+        assert!(type_lines("r1\t77\t*\t0\t0\t*\t*\t0\t0\t*\t*\tAS:i:100\n").is_empty());
+    }
+
+    #[test]
+    fn second_mate_is_left() {
+        let lines = type_lines("r1\t145\tchr1\t1000\t60\t20S80M\t*\t0\t0\t*\t*\tAS:i:80\n");
+        assert_eq!(lines, ["r1\tL\t80\tNA\t20S80M\tchr1\t1000\t-"]);
+    }
+}
@@ -0,0 +1,268 @@
+//! BAM index (`.bai`) loading and region lookups.
+
+use crate::bgzf::{invalid_data, read_u32, read_u64};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Bin holding per-reference metadata rather than alignments.
+const METADATA_BIN: u32 = 37450;
+
+/// A range of virtual file offsets `[start, end)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Chunk {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Default)]
+struct ReferenceIndex {
+    bins: HashMap<u32, Vec<Chunk>>,
+    /// Smallest virtual offset of alignments in each 16kb window.
+    intervals: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct BamIndex {
+    references: Vec<ReferenceIndex>,
+}
+
+impl BamIndex {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not open index {}: {}", path.display(), e),
+            )
+        })?;
+        BamIndex::read(BufReader::new(file))
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"BAI\x01" {
+            return Err(invalid_data("not a BAI index"));
+        }
+        let n_ref = read_u32(&mut reader)?;
+        let mut references = Vec::with_capacity(n_ref as usize);
+        for _ in 0..n_ref {
+            let mut index = ReferenceIndex::default();
+            for _ in 0..read_u32(&mut reader)? {
+                let bin = read_u32(&mut reader)?;
+                let n_chunk = read_u32(&mut reader)?;
+                let mut chunks = Vec::with_capacity(n_chunk as usize);
+                for _ in 0..n_chunk {
+                    chunks.push(Chunk {
+                        start: read_u64(&mut reader)?,
+                        end: read_u64(&mut reader)?,
+                    });
+                }
+                if bin != METADATA_BIN {
+                    index.bins.insert(bin, chunks);
+                }
+            }
+            for _ in 0..read_u32(&mut reader)? {
+                index.intervals.push(read_u64(&mut reader)?);
+            }
+            references.push(index);
+        }
+        Ok(BamIndex { references })
+    }
+
+    /// Merged, sorted chunks that may hold alignments overlapping the 0-based,
+    /// half-open region `[start, end)` of reference `ref_id`.
+    pub fn query(&self, ref_id: usize, start: u32, end: u32) -> Vec<Chunk> {
+        let Some(index) = self.references.get(ref_id) else {
+            return Vec::new();
+        };
+        let min_offset = index
+            .intervals
+            .get((start >> 14) as usize)
+            .copied()
+            .unwrap_or(0);
+        let mut chunks: Vec<Chunk> = region_to_bins(start, end)
+            .into_iter()
+            .filter_map(|bin| index.bins.get(&bin))
+            .flatten()
+            .filter(|c| c.end > min_offset)
+            .copied()
+            .collect();
+        chunks.sort();
+
+        let mut merged: Vec<Chunk> = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            match merged.last_mut() {
+                Some(last) if chunk.start <= last.end => last.end = last.end.max(chunk.end),
+                _ => merged.push(chunk),
+            }
+        }
+        merged
+    }
+}
+
+/// Bins that can contain alignments overlapping `[start, end)`, as in the SAM spec.
+fn region_to_bins(start: u32, end: u32) -> Vec<u32> {
+    let end = end.max(start + 1) - 1;
+    let mut bins = vec![0];
+    for (offset, shift) in [(1, 26), (9, 23), (73, 20), (585, 17), (4681, 14)] {
+        bins.extend((offset + (start >> shift))..=(offset + (end >> shift)));
+    }
+    bins
+}
+
+/// Builds the `.bai` of an in-memory, coordinate-sorted BAM, with one chunk per
+/// record, for tests of region queries.
+#[cfg(test)]
+pub(crate) fn index_bytes(bam: &[u8]) -> Vec<u8> {
+    use crate::bam::Record;
+    use crate::bgzf::{read_exact_or_eof, BgzfReader};
+
+    let mut reader = BgzfReader::new(bam);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).unwrap();
+    let l_text = read_u32(&mut reader).unwrap() as usize;
+    io::copy(&mut (&mut reader).take(l_text as u64), &mut io::sink()).unwrap();
+    let n_ref = read_u32(&mut reader).unwrap() as usize;
+    for _ in 0..n_ref {
+        let l_name = read_u32(&mut reader).unwrap() as u64;
+        io::copy(&mut (&mut reader).take(l_name + 4), &mut io::sink()).unwrap();
+    }
+
+    let mut references: Vec<ReferenceIndex> =
+        (0..n_ref).map(|_| ReferenceIndex::default()).collect();
+    loop {
+        let start = reader.virtual_position();
+        let mut size = [0u8; 4];
+        if !read_exact_or_eof(&mut reader, &mut size).unwrap() {
+            break;
+        }
+        let mut data = vec![0u8; u32::from_le_bytes(size) as usize];
+        reader.read_exact(&mut data).unwrap();
+        let record = Record::decode(&data).unwrap();
+        let chunk = Chunk {
+            start,
+            end: reader.virtual_position(),
+        };
+        let Some(index) = usize::try_from(record.ref_id)
+            .ok()
+            .and_then(|i| references.get_mut(i))
+        else {
+            continue;
+        };
+        index
+            .bins
+            .entry(u32::from(record.bin))
+            .or_default()
+            .push(chunk);
+        let last_window = ((record.end() - 1).max(record.pos) >> 14) as usize;
+        if index.intervals.len() <= last_window {
+            index.intervals.resize(last_window + 1, u64::MAX);
+        }
+        for window in (record.pos >> 14) as usize..=last_window {
+            index.intervals[window] = index.intervals[window].min(start);
+        }
+    }
+
+    let mut out = b"BAI\x01".to_vec();
+    out.extend_from_slice(&(n_ref as u32).to_le_bytes());
+    for index in &references {
+        out.extend_from_slice(&(index.bins.len() as u32).to_le_bytes());
+        for (bin, chunks) in &index.bins {
+            out.extend_from_slice(&bin.to_le_bytes());
+            out.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+            for chunk in chunks {
+                out.extend_from_slice(&chunk.start.to_le_bytes());
+                out.extend_from_slice(&chunk.end.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&(index.intervals.len() as u32).to_le_bytes());
+        for offset in &index.intervals {
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::{reg2bin, BamReader, BamWriter, Header, Record, Reference};
+    use std::io::Cursor;
+
+    /// 100bp reads every 10bp along the first 200kb of chr1, and a few on chr2.
+    fn reads() -> Vec<Record> {
+        let read = |ref_id: i32, pos: i32| Record {
+            qname: format!("r{}_{}", ref_id, pos),
+            ref_id,
+            pos,
+            bin: reg2bin(pos, pos + 100),
+            cigar: "100M".parse().unwrap(),
+            seq: vec![b'A'; 100],
+            qual: vec![30; 100],
+            next_ref_id: -1,
+            next_pos: -1,
+            ..Record::default()
+        };
+        (0..20_000)
+            .map(|i| read(0, i * 10))
+            .chain((0..10).map(|i| read(1, i * 1000)))
+            .collect()
+    }
+
+    fn bam(records: &[Record]) -> Vec<u8> {
+        let reference = |name: &str| Reference {
+            name: name.to_string(),
+            length: 1_000_000,
+        };
+        let header = Header {
+            text: "@HD\tVN:1.6\tSO:coordinate\n".to_string(),
+            references: vec![reference("chr1"), reference("chr2")],
+        };
+        let mut writer = BamWriter::new(Vec::new(), &header).unwrap();
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn region_queries_match_a_scan() {
+        let records = reads();
+        let bytes = bam(&records);
+        let index = BamIndex::read(&index_bytes(&bytes)[..]).unwrap();
+        let mut reader = BamReader::new(Cursor::new(bytes)).unwrap();
+        for (ref_id, start, end) in [
+            (0, 0, 1),
+            (0, 30_000, 30_001),
+            (0, 16_380, 16_400),
+            (0, 65_000, 140_000),
+            (0, 199_990, 400_000),
+            (1, 0, 1_000_000),
+            (1, 500, 900),
+        ] {
+            let names = |records: Vec<&Record>| -> Vec<String> {
+                records.into_iter().map(|r| r.qname.clone()).collect()
+            };
+            let expected = names(
+                records
+                    .iter()
+                    .filter(|r| r.ref_id == ref_id as i32)
+                    .filter(|r| r.pos < end as i32 && r.end() > start as i32)
+                    .collect(),
+            );
+            let found = reader.query(&index, ref_id, start, end).unwrap();
+            assert_eq!(
+                names(found.iter().collect()),
+                expected,
+                "{}:{}-{}",
+                ref_id,
+                start,
+                end
+            );
+        }
+        assert!(index.query(2, 0, 100).is_empty());
+    }
+}
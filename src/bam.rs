@@ -0,0 +1,571 @@
+//! Minimal BAM reading and writing on top of [`crate::bgzf`].
+
+use crate::bai::BamIndex;
+use crate::bgzf::{invalid_data, read_exact_or_eof, read_u32, BgzfReader, BgzfWriter};
+use crate::tags::{self, AuxValue};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// SAM flag bits used by the pipeline.
+pub mod flags {
+    pub const PAIRED: u16 = 0x1;
+    pub const PROPER_PAIR: u16 = 0x2;
+    pub const UNMAPPED: u16 = 0x4;
+    pub const MATE_UNMAPPED: u16 = 0x8;
+    pub const REVERSE: u16 = 0x10;
+    pub const FIRST_IN_PAIR: u16 = 0x40;
+    pub const SECOND_IN_PAIR: u16 = 0x80;
+    pub const SECONDARY: u16 = 0x100;
+    pub const DUPLICATE: u16 = 0x400;
+    pub const SUPPLEMENTARY: u16 = 0x800;
+}
+
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+const CIGAR_CODES: &[u8; 9] = b"MIDNSHP=X";
+
+#[derive(Debug, Clone, Default)]
+pub struct Reference {
+    pub name: String,
+    pub length: u32,
+}
+
+/// The SAM header text plus the binary reference dictionary.
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    pub text: String,
+    pub references: Vec<Reference>,
+}
+
+impl Header {
+    pub fn reference_name(&self, ref_id: i32) -> &str {
+        usize::try_from(ref_id)
+            .ok()
+            .and_then(|i| self.references.get(i))
+            .map_or("*", |r| r.name.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CigarOp {
+    /// Operation as its SAM character, e.g. `b'M'` or `b'S'`.
+    pub op: u8,
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cigar(pub Vec<CigarOp>);
+
+impl Cigar {
+    /// Length of a soft clip at the start of the read, ignoring hard clips.
+    pub fn leading_soft_clip(&self) -> u32 {
+        self.0
+            .iter()
+            .find(|c| c.op != b'H')
+            .filter(|c| c.op == b'S')
+            .map_or(0, |c| c.len)
+    }
+
+    /// Length of a soft clip at the end of the read, ignoring hard clips.
+    pub fn trailing_soft_clip(&self) -> u32 {
+        self.0
+            .iter()
+            .rev()
+            .find(|c| c.op != b'H')
+            .filter(|c| c.op == b'S')
+            .map_or(0, |c| c.len)
+    }
+
+    /// Number of reference bases covered by the alignment.
+    pub fn reference_len(&self) -> u32 {
+        self.0
+            .iter()
+            .filter(|c| matches!(c.op, b'M' | b'D' | b'N' | b'=' | b'X'))
+            .map(|c| c.len)
+            .sum()
+    }
+}
+
+impl FromStr for Cigar {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        if s == "*" {
+            return Ok(Cigar::default());
+        }
+        let mut ops = Vec::new();
+        let mut len: u32 = 0;
+        for b in s.bytes() {
+            if b.is_ascii_digit() {
+                len = len
+                    .checked_mul(10)
+                    .and_then(|l| l.checked_add(u32::from(b - b'0')))
+                    .ok_or_else(|| invalid_data("CIGAR operation too long"))?;
+            } else if CIGAR_CODES.contains(&b) {
+                ops.push(CigarOp { op: b, len });
+                len = 0;
+            } else {
+                return Err(invalid_data(&format!("invalid CIGAR '{}'", s)));
+            }
+        }
+        Ok(Cigar(ops))
+    }
+}
+
+impl fmt::Display for Cigar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "*");
+        }
+        for c in &self.0 {
+            write!(f, "{}{}", c.len, c.op as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// One alignment record. Positions are 0-based as stored in BAM; `seq` holds
+/// ASCII bases and `qual` raw phred scores (`0xff` when absent).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Record {
+    pub qname: String,
+    pub flag: u16,
+    pub ref_id: i32,
+    pub pos: i32,
+    pub mapq: u8,
+    pub bin: u16,
+    pub cigar: Cigar,
+    pub next_ref_id: i32,
+    pub next_pos: i32,
+    pub tlen: i32,
+    pub seq: Vec<u8>,
+    pub qual: Vec<u8>,
+    /// Optional fields in their binary BAM encoding.
+    pub aux: Vec<u8>,
+}
+
+impl Record {
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flag & flag != 0
+    }
+
+    pub fn is_unmapped(&self) -> bool {
+        self.has_flag(flags::UNMAPPED)
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.has_flag(flags::REVERSE)
+    }
+
+    /// 0-based exclusive end of the alignment on the reference.
+    pub fn end(&self) -> i32 {
+        self.pos + self.cigar.reference_len().max(1) as i32
+    }
+
+    /// True for secondary and supplementary alignments.
+    pub fn is_secondary_or_supplementary(&self) -> bool {
+        self.has_flag(flags::SECONDARY | flags::SUPPLEMENTARY)
+    }
+
+    /// The value of optional field `tag`. Fields after a malformed one are not seen.
+    /// Tags without a dedicated accessor, such as `NM`, `SA` or `XA`, are read
+    /// through this as typed [`AuxValue`]s.
+    pub fn aux(&self, tag: &[u8; 2]) -> Option<AuxValue> {
+        tags::fields(&self.aux)
+            .map_while(Result::ok)
+            .find(|field| &field.tag == tag)
+            .map(|field| field.value)
+    }
+
+    /// Aligner score of this alignment (`AS`).
+    pub fn alignment_score(&self) -> Option<i32> {
+        self.aux(b"AS")?.as_i32()
+    }
+
+    /// Aligner score of the best other alignment (`XS`); absent when the read
+    /// has no suboptimal hit.
+    pub fn suboptimal_score(&self) -> Option<i32> {
+        self.aux(b"XS")?.as_i32()
+    }
+
+    /// Decodes a record from its bytes, excluding the leading `block_size`.
+    pub fn decode(data: &[u8]) -> io::Result<Record> {
+        if data.len() < 32 {
+            return Err(invalid_data("truncated BAM record"));
+        }
+        let i32_at =
+            |i: usize| i32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let l_read_name = data[8] as usize;
+        let n_cigar = u16_at(12) as usize;
+        let l_seq = i32_at(16) as usize;
+
+        let name_end = 32 + l_read_name;
+        let cigar_end = name_end + 4 * n_cigar;
+        let seq_end = cigar_end + l_seq.div_ceil(2);
+        let qual_end = seq_end + l_seq;
+        if data.len() < qual_end || l_read_name == 0 {
+            return Err(invalid_data("truncated BAM record"));
+        }
+
+        let cigar = (0..n_cigar)
+            .map(|k| {
+                let v = i32_at(name_end + 4 * k) as u32;
+                CigarOp {
+                    op: CIGAR_CODES.get((v & 0xf) as usize).copied().unwrap_or(b'?'),
+                    len: v >> 4,
+                }
+            })
+            .collect();
+        let seq = (0..l_seq)
+            .map(|k| {
+                let byte = data[cigar_end + k / 2];
+                let code = if k % 2 == 0 { byte >> 4 } else { byte & 0xf };
+                SEQ_CODES[code as usize]
+            })
+            .collect();
+
+        Ok(Record {
+            qname: String::from_utf8_lossy(&data[32..name_end - 1]).into_owned(),
+            flag: u16_at(14),
+            ref_id: i32_at(0),
+            pos: i32_at(4),
+            mapq: data[9],
+            bin: u16_at(10),
+            cigar: Cigar(cigar),
+            next_ref_id: i32_at(20),
+            next_pos: i32_at(24),
+            tlen: i32_at(28),
+            seq,
+            qual: data[seq_end..qual_end].to_vec(),
+            aux: data[qual_end..].to_vec(),
+        })
+    }
+
+    /// Appends the binary encoding of the record, including `block_size`, to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&self.ref_id.to_le_bytes());
+        buf.extend_from_slice(&self.pos.to_le_bytes());
+        buf.push((self.qname.len() + 1) as u8);
+        buf.push(self.mapq);
+        buf.extend_from_slice(&self.bin.to_le_bytes());
+        buf.extend_from_slice(&(self.cigar.0.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&self.flag.to_le_bytes());
+        buf.extend_from_slice(&(self.seq.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.next_ref_id.to_le_bytes());
+        buf.extend_from_slice(&self.next_pos.to_le_bytes());
+        buf.extend_from_slice(&self.tlen.to_le_bytes());
+        buf.extend_from_slice(self.qname.as_bytes());
+        buf.push(0);
+        for c in &self.cigar.0 {
+            let code = CIGAR_CODES.iter().position(|&b| b == c.op).unwrap_or(0) as u32;
+            buf.extend_from_slice(&((c.len << 4) | code).to_le_bytes());
+        }
+        for pair in self.seq.chunks(2) {
+            let code = |b: u8| {
+                SEQ_CODES
+                    .iter()
+                    .position(|&s| s == b.to_ascii_uppercase())
+                    .unwrap_or(15) as u8
+            };
+            let hi = code(pair[0]) << 4;
+            let lo = pair.get(1).map_or(0, |&b| code(b));
+            buf.push(hi | lo);
+        }
+        if self.qual.len() == self.seq.len() {
+            buf.extend_from_slice(&self.qual);
+        } else {
+            buf.resize(buf.len() + self.seq.len(), 0xff);
+        }
+        buf.extend_from_slice(&self.aux);
+        let block_size = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&block_size.to_le_bytes());
+    }
+}
+
+/// Smallest bin containing the 0-based, half-open interval `[start, end)`.
+pub fn reg2bin(start: i32, end: i32) -> u16 {
+    let end = end - 1;
+    for (offset, shift) in [(4681, 14), (585, 17), (73, 20), (9, 23), (1, 26)] {
+        if start >> shift == end >> shift {
+            return (offset + (start >> shift)) as u16;
+        }
+    }
+    0
+}
+
+pub struct BamReader<R: Read> {
+    inner: BgzfReader<R>,
+    header: Header,
+    buf: Vec<u8>,
+}
+
+impl BamReader<BufReader<File>> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not open {}: {}", path.display(), e),
+            )
+        })?;
+        BamReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> BamReader<R> {
+    /// Wraps a BGZF-compressed stream and reads the BAM header from it.
+    pub fn new(inner: R) -> io::Result<Self> {
+        let mut inner = BgzfReader::new(inner);
+        let mut magic = [0u8; 4];
+        inner.read_exact(&mut magic)?;
+        if &magic != b"BAM\x01" {
+            return Err(invalid_data("not a BAM file"));
+        }
+        let l_text = read_u32(&mut inner)? as usize;
+        let mut text = vec![0u8; l_text];
+        inner.read_exact(&mut text)?;
+        while text.last() == Some(&0) {
+            text.pop();
+        }
+        let n_ref = read_u32(&mut inner)?;
+        let mut references = Vec::with_capacity(n_ref as usize);
+        for _ in 0..n_ref {
+            let l_name = read_u32(&mut inner)? as usize;
+            let mut name = vec![0u8; l_name];
+            inner.read_exact(&mut name)?;
+            name.pop();
+            references.push(Reference {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                length: read_u32(&mut inner)?,
+            });
+        }
+        Ok(BamReader {
+            inner,
+            header: Header {
+                text: String::from_utf8_lossy(&text).into_owned(),
+                references,
+            },
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Reads the next record, returning `None` at the end of the file.
+    pub fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut size = [0u8; 4];
+        if !read_exact_or_eof(&mut self.inner, &mut size)? {
+            return Ok(None);
+        }
+        self.buf.resize(u32::from_le_bytes(size) as usize, 0);
+        self.inner.read_exact(&mut self.buf)?;
+        Record::decode(&self.buf).map(Some)
+    }
+}
+
+impl<R: Read + Seek> BamReader<R> {
+    /// Alignments overlapping the 0-based, half-open region `[start, end)` of `ref_id`.
+    pub fn query(
+        &mut self,
+        index: &BamIndex,
+        ref_id: usize,
+        start: u32,
+        end: u32,
+    ) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        for chunk in index.query(ref_id, start, end) {
+            self.inner.seek_virtual(chunk.start)?;
+            while self.inner.virtual_position() < chunk.end {
+                let Some(record) = self.read_record()? else {
+                    break;
+                };
+                // records are coordinate sorted, nothing further can overlap
+                if record.ref_id != ref_id as i32 || record.pos >= end as i32 {
+                    break;
+                }
+                if record.end() > start as i32 {
+                    records.push(record);
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+impl<R: Read> Iterator for BamReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+pub struct BamWriter<W: Write> {
+    inner: BgzfWriter<W>,
+    buf: Vec<u8>,
+}
+
+impl BamWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, header: &Header) -> io::Result<Self> {
+        BamWriter::new(BufWriter::new(File::create(path)?), header)
+    }
+}
+
+impl<W: Write> BamWriter<W> {
+    pub fn new(inner: W, header: &Header) -> io::Result<Self> {
+        let mut inner = BgzfWriter::new(inner);
+        inner.write_all(b"BAM\x01")?;
+        inner.write_all(&(header.text.len() as u32).to_le_bytes())?;
+        inner.write_all(header.text.as_bytes())?;
+        inner.write_all(&(header.references.len() as u32).to_le_bytes())?;
+        for r in &header.references {
+            inner.write_all(&(r.name.len() as u32 + 1).to_le_bytes())?;
+            inner.write_all(r.name.as_bytes())?;
+            inner.write_all(&[0])?;
+            inner.write_all(&r.length.to_le_bytes())?;
+        }
+        Ok(BamWriter {
+            inner,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.buf.clear();
+        record.encode(&mut self.buf);
+        self.inner.write_all(&self.buf)
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        self.inner.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::AuxField;
+
+    fn record_with(tags: &[&str]) -> Record {
+        let mut record = Record::default();
+        for tag in tags {
+            tag.parse::<AuxField>().unwrap().encode(&mut record.aux);
+        }
+        record
+    }
+
+    #[test]
+    fn scores() {
+        let record = record_with(&["AS:i:42", "XS:i:17"]);
+        assert_eq!(record.alignment_score(), Some(42));
+        assert_eq!(record.suboptimal_score(), Some(17));
+        let unique = record_with(&["AS:i:42"]);
+        assert_eq!(unique.suboptimal_score(), None);
+    }
+
+    #[test]
+    fn other_tags_are_typed() {
+        let record = record_with(&[
+            "NM:i:3",
+            "SA:Z:chr2,1500,-,30S70M,60,1;",
+            "XA:Z:chr1,-2001,100M,2;",
+        ]);
+        assert_eq!(record.aux(b"NM").and_then(|v| v.as_i32()), Some(3));
+        assert_eq!(
+            record.aux(b"SA"),
+            Some(AuxValue::String("chr2,1500,-,30S70M,60,1;".to_string()))
+        );
+        assert_eq!(
+            record.aux(b"XA"),
+            Some(AuxValue::String("chr1,-2001,100M,2;".to_string()))
+        );
+        assert_eq!(record.aux(b"MD"), None);
+    }
+
+    fn alignment() -> Record {
+        let mut record = record_with(&["AS:i:90", "RG:Z:lane1"]);
+        record.qname = "r1/1".to_string();
+        record.flag = flags::PAIRED | flags::FIRST_IN_PAIR | flags::REVERSE;
+        record.ref_id = 1;
+        record.pos = 999;
+        record.mapq = 60;
+        record.cigar = "5S10M2I3M".parse().unwrap();
+        record.bin = reg2bin(999, 999 + record.cigar.reference_len() as i32);
+        record.next_ref_id = 0;
+        record.next_pos = 4999;
+        record.tlen = -300;
+        // odd length, so the last base shares its byte with padding
+        record.seq = b"ACGTNACGTAACCGGTTACGT".to_vec();
+        record.qual = (0..21).collect();
+        record
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let record = alignment();
+        let mut buf = Vec::new();
+        record.encode(&mut buf);
+        let block_size = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        assert_eq!(block_size, buf.len() - 4);
+        assert_eq!(Record::decode(&buf[4..]).unwrap(), record);
+        assert!(Record::decode(&buf[4..40]).is_err());
+    }
+
+    #[test]
+    fn missing_qualities_are_encoded_as_0xff() {
+        let mut record = alignment();
+        record.qual.clear();
+        let mut buf = Vec::new();
+        record.encode(&mut buf);
+        assert_eq!(Record::decode(&buf[4..]).unwrap().qual, vec![0xff; 21]);
+    }
+
+    #[test]
+    fn bam_file_round_trip() {
+        let header = Header {
+            text: "@HD\tVN:1.6\tSO:unsorted\n".to_string(),
+            references: vec![
+                Reference {
+                    name: "chr1".to_string(),
+                    length: 248956422,
+                },
+                Reference {
+                    name: "chr2".to_string(),
+                    length: 242193529,
+                },
+            ],
+        };
+        // enough records to span several BGZF blocks
+        let records: Vec<Record> = (0..5000)
+            .map(|i| Record {
+                qname: format!("read{}", i),
+                pos: i,
+                ..alignment()
+            })
+            .collect();
+        let mut writer = BamWriter::new(Vec::new(), &header).unwrap();
+        for record in &records {
+            writer.write_record(record).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let reader = BamReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.header().text, header.text);
+        let names: Vec<(&str, u32)> = reader
+            .header()
+            .references
+            .iter()
+            .map(|r| (r.name.as_str(), r.length))
+            .collect();
+        assert_eq!(names, [("chr1", 248956422), ("chr2", 242193529)]);
+        let read: Vec<Record> = reader.collect::<io::Result<_>>().unwrap();
+        assert_eq!(read, records);
+    }
+}
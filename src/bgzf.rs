@@ -0,0 +1,357 @@
+//! BGZF (blocked gzip) reading and writing, the container format used by BAM.
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Largest amount of uncompressed data put in a single block.
+const MAX_BLOCK_DATA: usize = 0xff00;
+
+/// The empty block that terminates every BGZF file.
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Decompresses a BGZF stream block by block.
+pub struct BgzfReader<R> {
+    inner: R,
+    block: Vec<u8>,
+    pos: usize,
+    /// Compressed offset of the current block.
+    block_offset: u64,
+    /// Compressed offset of the block after the current one.
+    next_offset: u64,
+}
+
+impl<R: Read> BgzfReader<R> {
+    pub fn new(inner: R) -> Self {
+        BgzfReader {
+            inner,
+            block: Vec::new(),
+            pos: 0,
+            block_offset: 0,
+            next_offset: 0,
+        }
+    }
+
+    /// Virtual offset of the next byte to be read: the compressed block offset in
+    /// the upper 48 bits and the offset within the uncompressed block below.
+    pub fn virtual_position(&self) -> u64 {
+        if self.pos >= self.block.len() {
+            self.next_offset << 16
+        } else {
+            (self.block_offset << 16) | self.pos as u64
+        }
+    }
+
+    /// Loads the next non-empty block, returning false at the end of the stream.
+    fn read_block(&mut self) -> io::Result<bool> {
+        loop {
+            let mut header = [0u8; 12];
+            if !read_exact_or_eof(&mut self.inner, &mut header)? {
+                return Ok(false);
+            }
+            if header[0] != 0x1f || header[1] != 0x8b || header[2] != 8 || header[3] & 4 == 0 {
+                return Err(invalid_data("not a BGZF block"));
+            }
+            let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+            let mut extra = vec![0u8; xlen];
+            self.inner.read_exact(&mut extra)?;
+            let bsize = block_size(&extra).ok_or_else(|| invalid_data("missing BGZF BC field"))?;
+            let cdata_len = bsize
+                .checked_sub(xlen + 20)
+                .ok_or_else(|| invalid_data("corrupt BGZF block size"))?;
+            let mut cdata = vec![0u8; cdata_len];
+            self.inner.read_exact(&mut cdata)?;
+            let mut trailer = [0u8; 8];
+            self.inner.read_exact(&mut trailer)?;
+            self.block_offset = self.next_offset;
+            self.next_offset += bsize as u64;
+            let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let isize = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+            self.block.clear();
+            self.pos = 0;
+            DeflateDecoder::new(&cdata[..]).read_to_end(&mut self.block)?;
+            if self.block.len() != isize as usize {
+                return Err(invalid_data("BGZF block length mismatch"));
+            }
+            let mut check = Crc::new();
+            check.update(&self.block);
+            if check.sum() != crc {
+                return Err(invalid_data("BGZF block checksum mismatch"));
+            }
+            if !self.block.is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    /// Positions the reader at a virtual offset taken from a BAM index.
+    pub fn seek_virtual(&mut self, offset: u64) -> io::Result<()> {
+        let block_offset = offset >> 16;
+        self.inner.seek(SeekFrom::Start(block_offset))?;
+        self.next_offset = block_offset;
+        self.block.clear();
+        self.pos = 0;
+        if self.read_block()? {
+            self.pos = (offset & 0xffff) as usize;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.block.len() && !self.read_block()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Compresses data into BGZF blocks; call [`BgzfWriter::finish`] to write the EOF marker.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BgzfWriter {
+            inner,
+            buf: Vec::with_capacity(MAX_BLOCK_DATA),
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buf)?;
+        let mut cdata = encoder.finish()?;
+        if cdata.len() > MAX_BLOCK_DATA {
+            // incompressible input: stored deflate blocks always fit
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::none());
+            encoder.write_all(&self.buf)?;
+            cdata = encoder.finish()?;
+        }
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+
+        let bsize = (cdata.len() + 25) as u16;
+        self.inner.write_all(&[
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00,
+        ])?;
+        self.inner.write_all(&bsize.to_le_bytes())?;
+        self.inner.write_all(&cdata)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner
+            .write_all(&(self.buf.len() as u32).to_le_bytes())?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Flushes pending data, appends the EOF block and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(MAX_BLOCK_DATA - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == MAX_BLOCK_DATA {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Decompresses the gzip or BGZF files `inputs` one after another into `out`,
+/// returning the number of bytes written. Every member of a file is decoded, so
+/// BGZF and concatenated gzip files come out whole. Errors name the input file.
+pub fn decompress_concat<P: AsRef<Path>, W: Write>(inputs: &[P], out: &mut W) -> io::Result<u64> {
+    let mut total = 0;
+    for path in inputs {
+        let path = path.as_ref();
+        let context = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let file = File::open(path).map_err(context)?;
+        let mut decoder = MultiGzDecoder::new(BufReader::new(file));
+        total += io::copy(&mut decoder, out).map_err(context)?;
+    }
+    Ok(total)
+}
+
+/// Finds the BC subfield holding the total block size minus one.
+fn block_size(extra: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i + 4 <= extra.len() {
+        let slen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+        if extra[i] == b'B' && extra[i + 1] == b'C' && slen == 2 && i + 6 <= extra.len() {
+            return Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as usize + 1);
+        }
+        i += 4 + slen;
+    }
+    None
+}
+
+/// Like `read_exact`, but returns false instead of failing on a clean end of stream.
+pub(crate) fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Reads a little-endian `u32`.
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    reader.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+/// Reads a little-endian `u64`.
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    reader.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+    use flate2::write::GzEncoder;
+    use std::fs;
+
+    /// Poorly compressible bytes from a linear congruential generator.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn multi_block_round_trip() {
+        let data = noise(3 * MAX_BLOCK_DATA + 1000);
+        let bgzf = compress(&data);
+        assert!(bgzf.ends_with(&EOF_BLOCK));
+
+        let mut reader = BgzfReader::new(&bgzf[..]);
+        let mut blocks = 0;
+        while reader.read_block().unwrap() {
+            blocks += 1;
+        }
+        assert_eq!(blocks, 4);
+
+        let mut read = Vec::new();
+        BgzfReader::new(&bgzf[..]).read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        // every block is a gzip member, so plain gzip readers see the whole data
+        let mut read = Vec::new();
+        MultiGzDecoder::new(&bgzf[..])
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn corrupt_block_is_rejected() {
+        let mut bgzf = compress(b"ACGT");
+        let crc = bgzf.len() - EOF_BLOCK.len() - 8;
+        bgzf[crc] ^= 0xff;
+        let err = BgzfReader::new(&bgzf[..])
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "BGZF block checksum mismatch");
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn bgzf_and_multi_member_gzip_are_concatenated() {
+        let dir = TempDir::new("concat");
+        let data = noise(MAX_BLOCK_DATA + 100);
+        fs::write(dir.join("a.fq.gz"), compress(&data)).unwrap();
+        let mut members = gzip(b"@r1\nACGT\n+\nIIII\n");
+        members.extend(gzip(b"@r2\nTTGA\n+\nIIII\n"));
+        fs::write(dir.join("b.fq.gz"), members).unwrap();
+
+        let mut out = Vec::new();
+        let written =
+            decompress_concat(&[dir.join("a.fq.gz"), dir.join("b.fq.gz")], &mut out).unwrap();
+        let mut expected = data;
+        expected.extend_from_slice(b"@r1\nACGT\n+\nIIII\n@r2\nTTGA\n+\nIIII\n");
+        assert_eq!(written, expected.len() as u64);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn errors_name_the_corrupt_file() {
+        let dir = TempDir::new("concat-corrupt");
+        fs::write(dir.join("good.fq.gz"), gzip(b"ACGT")).unwrap();
+        let mut bad = gzip(b"ACGT");
+        let len = bad.len();
+        bad[len - 8] ^= 0xff;
+        fs::write(dir.join("bad.fq.gz"), bad).unwrap();
+
+        let inputs = [dir.join("good.fq.gz"), dir.join("bad.fq.gz")];
+        let err = decompress_concat(&inputs, &mut Vec::new()).unwrap_err();
+        let prefix = format!("{}: ", inputs[1].display());
+        assert!(err.to_string().starts_with(&prefix), "{}", err);
+
+        let missing = [dir.join("missing.fq.gz")];
+        let err = decompress_concat(&missing, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .starts_with(&format!("{}: ", missing[0].display())));
+    }
+}
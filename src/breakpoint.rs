@@ -0,0 +1,172 @@
+//! Breakpoint evidence: one line per supporting read in `<id>_all_breakpoint`.
+//!
+//! Lines are tab-separated with the columns
+//! `read_id evidence chrom position strand flank te_name te_position te_strand`:
+//! - `evidence` is `chimeric` (a human-anchored mate whose partner hit a TE) or
+//!   `split` (a soft-clipped segment that hit a TE);
+//! - `position` is 1-based: the anchor's alignment start for chimeric reads and the
+//!   aligned base next to the clip for split reads;
+//! - `strand` is the orientation of the human-aligned read;
+//! - `flank` is `L` when the human sequence lies left of the insertion, `R` when right;
+//! - `te_name`, `te_position` (1-based) and `te_strand` describe the TE alignment.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Evidence {
+    Chimeric,
+    Split,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flank {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub read_id: String,
+    pub evidence: Evidence,
+    pub chrom: String,
+    pub position: u32,
+    pub strand: Strand,
+    pub flank: Flank,
+    pub te_name: String,
+    pub te_position: u32,
+    pub te_strand: Strand,
+}
+
+impl Breakpoint {
+    /// Orientation of the inserted TE relative to the reference genome.
+    ///
+    /// Soft-clipped segments keep the genome orientation of their read, so the TE
+    /// strand is the insertion strand. For chimeric pairs the TE-aligned mate faces
+    /// the anchor, so the insertion is forward when the two strands differ.
+    pub fn insertion_strand(&self) -> Strand {
+        match self.evidence {
+            Evidence::Split => self.te_strand,
+            Evidence::Chimeric if self.strand != self.te_strand => Strand::Forward,
+            Evidence::Chimeric => Strand::Reverse,
+        }
+    }
+}
+
+impl fmt::Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Evidence::Chimeric => "chimeric",
+            Evidence::Split => "split",
+        })
+    }
+}
+
+impl FromStr for Evidence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "chimeric" => Ok(Evidence::Chimeric),
+            "split" => Ok(Evidence::Split),
+            _ => Err(anyhow!("unknown evidence type '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Strand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+        })
+    }
+}
+
+impl FromStr for Strand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "+" => Ok(Strand::Forward),
+            "-" => Ok(Strand::Reverse),
+            _ => Err(anyhow!("unknown strand '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Flank {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Flank::Left => "L",
+            Flank::Right => "R",
+        })
+    }
+}
+
+impl FromStr for Flank {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "L" => Ok(Flank::Left),
+            "R" => Ok(Flank::Right),
+            _ => Err(anyhow!("unknown flank '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.read_id,
+            self.evidence,
+            self.chrom,
+            self.position,
+            self.strand,
+            self.flank,
+            self.te_name,
+            self.te_position,
+            self.te_strand
+        )
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 9 {
+            return Err(anyhow!("expected 9 columns, found {}", fields.len()));
+        }
+        Ok(Breakpoint {
+            read_id: fields[0].to_string(),
+            evidence: fields[1].parse()?,
+            chrom: fields[2].to_string(),
+            position: fields[3].parse()?,
+            strand: fields[4].parse()?,
+            flank: fields[5].parse()?,
+            te_name: fields[6].to_string(),
+            te_position: fields[7].parse()?,
+            te_strand: fields[8].parse()?,
+        })
+    }
+}
+
+pub fn write_breakpoints<W: Write>(out: &mut W, breakpoints: &[Breakpoint]) -> Result<()> {
+    for breakpoint in breakpoints {
+        writeln!(out, "{}", breakpoint)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! `bwa mem` with typed parameters.
+
+use crate::aligner::{Aligner, Reads};
+use crate::cmd::Cmd;
+
+#[derive(Debug, Clone)]
+pub struct BwaMem {
+    /// The bwa executable.
+    pub program: String,
+    /// `-t`
+    pub threads: u32,
+    /// `-k`: minimum seed length.
+    pub min_seed_len: u32,
+    /// `-r`: re-seed when a MEM is longer than this factor times `-k`.
+    pub reseed_ratio: f32,
+    /// `-c`: skip seeds with more occurrences than this.
+    pub max_occurrences: u32,
+    /// `-m`: rounds of mate rescue.
+    pub mate_rescue_rounds: u32,
+    /// `-T`: minimum score to output an alignment.
+    pub min_score: u32,
+    /// `-h`: report up to this many alternative hits in `XA`.
+    pub max_alt_hits: u32,
+    /// `-a`: output all alignments, including unpaired and secondary ones.
+    pub all_alignments: bool,
+    /// `-Y`: soft-clip supplementary alignments.
+    pub soft_clip_supplementary: bool,
+    /// `-M`: mark shorter split hits as secondary.
+    pub mark_secondary: bool,
+}
+
+impl BwaMem {
+    /// bwa's own defaults.
+    pub fn new(threads: u32) -> Self {
+        BwaMem {
+            program: "bwa".to_string(),
+            threads,
+            min_seed_len: 19,
+            reseed_ratio: 1.5,
+            max_occurrences: 500,
+            mate_rescue_rounds: 50,
+            min_score: 30,
+            max_alt_hits: 5,
+            all_alignments: false,
+            soft_clip_supplementary: false,
+            mark_secondary: false,
+        }
+    }
+
+    /// ERVcaller's settings for aligning candidate reads to the TE library, which
+    /// keep every hit of repetitive seeds so that all TE families are reported.
+    pub fn te_library(threads: u32, min_score: u32) -> Self {
+        BwaMem {
+            max_occurrences: 100000,
+            min_score,
+            max_alt_hits: 10000,
+            all_alignments: true,
+            soft_clip_supplementary: true,
+            mark_secondary: true,
+            ..BwaMem::new(threads)
+        }
+    }
+}
+
+impl Aligner for BwaMem {
+    fn command(&self, reference: &str, reads: &Reads) -> Cmd {
+        let mut cmd = Cmd::new(&self.program).args([
+            "mem".to_string(),
+            "-t".to_string(),
+            self.threads.to_string(),
+            "-k".to_string(),
+            self.min_seed_len.to_string(),
+            "-r".to_string(),
+            self.reseed_ratio.to_string(),
+            "-c".to_string(),
+            self.max_occurrences.to_string(),
+            "-m".to_string(),
+            self.mate_rescue_rounds.to_string(),
+            "-T".to_string(),
+            self.min_score.to_string(),
+            "-h".to_string(),
+            self.max_alt_hits.to_string(),
+        ]);
+        for (set, flag) in [
+            (self.all_alignments, "-a"),
+            (self.soft_clip_supplementary, "-Y"),
+            (self.mark_secondary, "-M"),
+        ] {
+            if set {
+                cmd = cmd.arg(flag);
+            }
+        }
+        if let Reads::Interleaved(_) = reads {
+            // smart pairing of an interleaved file
+            cmd = cmd.arg("-p");
+        }
+        cmd.arg(reference).args(reads.files())
+    }
+}
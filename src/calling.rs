@@ -0,0 +1,418 @@
+//! Turns per-read breakpoint evidence into candidate TE insertions.
+
+use crate::breakpoint::{Breakpoint, Evidence, Flank, Strand};
+use crate::genotype::Genotype;
+use crate::vcf;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+/// Header of the per-sample insertion table.
+pub const INSERTION_HEADER: &str = "Sample_ID\tIs_Split_mode\tChrom\tPosition\tLeft_breakpoint\tRight_breakpoint\tTE_family\tStrand\tTSD_length\tChimeric_reads\tSplit_reads\tTotal_reads\tReference_reads\tPL\tGQ\tGenotype";
+
+#[derive(Debug, Clone)]
+pub struct CallingParams {
+    /// Largest gap between neighbouring reads of the same cluster, normally twice the
+    /// library insert size so that both flanks of an insertion fall in one cluster.
+    pub window: u32,
+    /// Largest distance between the left and right split breakpoints that is still
+    /// reported as a target site duplication.
+    pub tsd_window: u32,
+    /// Minimum number of distinct supporting reads (`-n`).
+    pub min_reads: u32,
+    pub read_len: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Insertion {
+    pub chrom: String,
+    /// Best estimate of the insertion site, 1-based.
+    pub position: u32,
+    /// Last reference base before the insertion, when split reads pin it down.
+    pub left_breakpoint: Option<u32>,
+    /// First reference base after the insertion, when split reads pin it down.
+    pub right_breakpoint: Option<u32>,
+    pub te_family: String,
+    pub strand: Strand,
+    pub tsd_len: Option<u32>,
+    pub chimeric_reads: u32,
+    pub split_reads: u32,
+    /// Filled in by the genotyping step (`-G`).
+    pub genotype: Option<Genotype>,
+}
+
+impl Insertion {
+    pub fn supporting_reads(&self) -> u32 {
+        self.chimeric_reads + self.split_reads
+    }
+}
+
+/// Insertions called jointly over several samples.
+#[derive(Debug, Clone, Default)]
+pub struct JointCalls {
+    /// One insertion per site, with the supporting reads of all samples.
+    pub sites: Vec<Insertion>,
+    /// Per sample, the same sites with only that sample's supporting reads.
+    pub samples: Vec<Vec<Insertion>>,
+}
+
+impl JointCalls {
+    /// Orders the sites like the reference contigs, moving each sample's entry of a
+    /// site along with it so that index `i` stays the same site everywhere.
+    pub fn sort_by_contig(&mut self, contigs: &[(String, u64)]) {
+        let key = vcf::contig_order(contigs);
+        let mut order: Vec<usize> = (0..self.sites.len()).collect();
+        order.sort_by_key(|&i| key(&self.sites[i]));
+        let permute = |insertions: &mut Vec<Insertion>| {
+            let mut taken: Vec<Option<Insertion>> = insertions.drain(..).map(Some).collect();
+            insertions.extend(order.iter().filter_map(|&i| taken[i].take()));
+        };
+        permute(&mut self.sites);
+        for insertions in &mut self.samples {
+            permute(insertions);
+        }
+    }
+}
+
+/// Clusters breakpoint evidence per chromosome, merges each cluster into one
+/// insertion and drops those with fewer than `min_reads` supporting reads.
+pub fn call_insertions(mut evidence: Vec<Breakpoint>, params: &CallingParams) -> Vec<Insertion> {
+    evidence.sort_by(|a, b| (&a.chrom, a.position).cmp(&(&b.chrom, b.position)));
+
+    let mut insertions: Vec<Insertion> = evidence
+        .chunk_by(|a, b| same_cluster(a, b, params))
+        .filter_map(|cluster| merge_cluster(&cluster.iter().collect::<Vec<_>>(), params))
+        .collect();
+    insertions.retain(|i| i.supporting_reads() >= params.min_reads);
+    insertions
+}
+
+/// Clusters the pooled evidence of all samples, one entry of `evidence` per
+/// sample, so that an insertion shared by several samples is one site. A site is
+/// kept when at least one sample has `min_reads` supporting reads.
+pub fn call_joint_insertions(evidence: &[Vec<Breakpoint>], params: &CallingParams) -> JointCalls {
+    let mut pooled: Vec<(usize, &Breakpoint)> = evidence
+        .iter()
+        .enumerate()
+        .flat_map(|(sample, breakpoints)| breakpoints.iter().map(move |bp| (sample, bp)))
+        .collect();
+    pooled.sort_by(|(_, a), (_, b)| (&a.chrom, a.position).cmp(&(&b.chrom, b.position)));
+
+    let mut calls = JointCalls {
+        sites: Vec::new(),
+        samples: vec![Vec::new(); evidence.len()],
+    };
+    for cluster in pooled.chunk_by(|(_, a), (_, b)| same_cluster(a, b, params)) {
+        let breakpoints: Vec<&Breakpoint> = cluster.iter().map(|(_, bp)| *bp).collect();
+        let Some(mut site) = merge_cluster(&breakpoints, params) else {
+            continue;
+        };
+        // read names are only unique within a sample, so support is counted per sample
+        let per_sample: Vec<Insertion> = (0..evidence.len())
+            .map(|sample| {
+                let own: Vec<&Breakpoint> = cluster
+                    .iter()
+                    .filter(|(s, _)| *s == sample)
+                    .map(|(_, bp)| *bp)
+                    .collect();
+                let (chimeric_reads, split_reads) = support(&own, &site.te_family);
+                Insertion {
+                    chimeric_reads,
+                    split_reads,
+                    ..site.clone()
+                }
+            })
+            .collect();
+        if per_sample
+            .iter()
+            .all(|i| i.supporting_reads() < params.min_reads)
+        {
+            continue;
+        }
+        site.chimeric_reads = per_sample.iter().map(|i| i.chimeric_reads).sum();
+        site.split_reads = per_sample.iter().map(|i| i.split_reads).sum();
+        calls.sites.push(site);
+        for (sample, insertion) in per_sample.into_iter().enumerate() {
+            calls.samples[sample].push(insertion);
+        }
+    }
+    calls
+}
+
+/// Whether `b`, which sorts after `a`, is close enough to belong to its cluster.
+fn same_cluster(a: &Breakpoint, b: &Breakpoint, params: &CallingParams) -> bool {
+    a.chrom == b.chrom && b.position - a.position <= params.window
+}
+
+/// Distinct chimeric and split reads among `breakpoints` that hit `family`.
+fn support(breakpoints: &[&Breakpoint], family: &str) -> (u32, u32) {
+    let mut seen = HashSet::new();
+    let (mut chimeric, mut split) = (0, 0);
+    for bp in breakpoints {
+        if bp.te_name == family && seen.insert((&bp.read_id, bp.evidence)) {
+            match bp.evidence {
+                Evidence::Chimeric => chimeric += 1,
+                Evidence::Split => split += 1,
+            }
+        }
+    }
+    (chimeric, split)
+}
+
+/// Merges one cluster into an insertion of its best supported TE family.
+fn merge_cluster(cluster: &[&Breakpoint], params: &CallingParams) -> Option<Insertion> {
+    let first = cluster.first()?;
+
+    // a read can hit several TE copies; count it once per family
+    let mut families: BTreeMap<&str, HashSet<&str>> = BTreeMap::new();
+    for bp in cluster {
+        families.entry(&bp.te_name).or_default().insert(&bp.read_id);
+    }
+    let (family, _) = families
+        .iter()
+        .max_by(|a, b| a.1.len().cmp(&b.1.len()).then(b.0.cmp(a.0)))?;
+
+    let mut seen = HashSet::new();
+    let reads: Vec<&Breakpoint> = cluster
+        .iter()
+        .copied()
+        .filter(|bp| bp.te_name == *family && seen.insert((&bp.read_id, bp.evidence)))
+        .collect();
+
+    let split_positions = |flank: Flank| -> Vec<u32> {
+        reads
+            .iter()
+            .filter(|bp| bp.evidence == Evidence::Split && bp.flank == flank)
+            .map(|bp| bp.position)
+            .collect()
+    };
+    let left_breakpoint = most_common(&split_positions(Flank::Left));
+    let right_breakpoint = most_common(&split_positions(Flank::Right));
+
+    let tsd_len = match (left_breakpoint, right_breakpoint) {
+        (Some(l), Some(r)) if l >= r && l - r < params.tsd_window => Some(l - r + 1),
+        // adjacent breakpoints: a clean junction without duplication
+        (Some(l), Some(r)) if r == l + 1 => Some(0),
+        // breakpoints too far apart to be one junction
+        _ => None,
+    };
+
+    let position = left_breakpoint.or(right_breakpoint).unwrap_or_else(|| {
+        // chimeric anchors only bound the site: left-flank anchors end before it,
+        // right-flank anchors start after it
+        let left = reads
+            .iter()
+            .filter(|bp| bp.flank == Flank::Left)
+            .map(|bp| bp.position + params.read_len - 1)
+            .max();
+        let right = reads
+            .iter()
+            .filter(|bp| bp.flank == Flank::Right)
+            .map(|bp| bp.position)
+            .min();
+        match (left, right) {
+            (Some(l), Some(r)) => (l + r) / 2,
+            (Some(p), None) | (None, Some(p)) => p,
+            (None, None) => first.position,
+        }
+    });
+
+    let forward = reads
+        .iter()
+        .filter(|bp| bp.insertion_strand() == Strand::Forward)
+        .count();
+    let strand = if 2 * forward >= reads.len() {
+        Strand::Forward
+    } else {
+        Strand::Reverse
+    };
+
+    let (chimeric_reads, split_reads) = support(&reads, family);
+    Some(Insertion {
+        chrom: first.chrom.clone(),
+        position,
+        left_breakpoint,
+        right_breakpoint,
+        te_family: family.to_string(),
+        strand,
+        tsd_len,
+        chimeric_reads,
+        split_reads,
+        genotype: None,
+    })
+}
+
+/// Most frequent value, preferring the smallest one on ties.
+fn most_common(values: &[u32]) -> Option<u32> {
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for &v in values {
+        *counts.entry(v).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(v, _)| v)
+}
+
+/// Writes the per-sample insertion table, one candidate per line.
+pub fn write_insertions<W: Write>(
+    out: &mut W,
+    sample_id: &str,
+    split_mode: bool,
+    insertions: &[Insertion],
+) -> Result<()> {
+    writeln!(out, "{}", INSERTION_HEADER)?;
+    let optional = |v: Option<u32>| v.map_or_else(|| "NA".to_string(), |v| v.to_string());
+    for insertion in insertions {
+        let genotype = match &insertion.genotype {
+            Some(g) => format!(
+                "{}\t{},{},{}\t{}\t{}",
+                g.ref_reads, g.pl[0], g.pl[1], g.pl[2], g.gq, g.call
+            ),
+            None => "NA\tNA\tNA\tNA".to_string(),
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            sample_id,
+            if split_mode { "Yes" } else { "No" },
+            insertion.chrom,
+            insertion.position,
+            optional(insertion.left_breakpoint),
+            optional(insertion.right_breakpoint),
+            insertion.te_family,
+            insertion.strand,
+            optional(insertion.tsd_len),
+            insertion.chimeric_reads,
+            insertion.split_reads,
+            insertion.supporting_reads(),
+            genotype
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(read_id: &str, position: u32, flank: Flank) -> Breakpoint {
+        Breakpoint {
+            read_id: read_id.to_string(),
+            evidence: Evidence::Split,
+            chrom: "chr1".to_string(),
+            position,
+            strand: Strand::Forward,
+            flank,
+            te_name: "HERVK".to_string(),
+            te_position: 1,
+            te_strand: Strand::Forward,
+        }
+    }
+
+    fn tsd(left: u32, right: u32) -> Option<u32> {
+        let params = CallingParams {
+            window: 1000,
+            tsd_window: 20,
+            min_reads: 1,
+            read_len: 100,
+        };
+        let evidence = vec![
+            split("a", left, Flank::Left),
+            split("b", right, Flank::Right),
+        ];
+        call_insertions(evidence, &params)[0].tsd_len
+    }
+
+    #[test]
+    fn overlapping_breakpoints_give_the_tsd_length() {
+        assert_eq!(tsd(1005, 1000), Some(6));
+    }
+
+    #[test]
+    fn adjacent_breakpoints_have_no_tsd() {
+        assert_eq!(tsd(1000, 1001), Some(0));
+    }
+
+    #[test]
+    fn distant_breakpoints_are_not_one_junction() {
+        assert_eq!(tsd(1000, 1100), None);
+        assert_eq!(tsd(1100, 1000), None);
+    }
+
+    fn chimeric(read_id: &str, chrom: &str, position: u32) -> Breakpoint {
+        Breakpoint {
+            evidence: Evidence::Chimeric,
+            chrom: chrom.to_string(),
+            ..split(read_id, position, Flank::Left)
+        }
+    }
+
+    const JOINT: CallingParams = CallingParams {
+        window: 1000,
+        tsd_window: 20,
+        min_reads: 3,
+        read_len: 100,
+    };
+
+    fn support_of(insertion: &Insertion) -> (u32, u32) {
+        (insertion.chimeric_reads, insertion.split_reads)
+    }
+
+    #[test]
+    fn joint_support_is_counted_per_sample() {
+        let evidence = vec![
+            vec![
+                chimeric("a", "chr1", 1000),
+                chimeric("b", "chr1", 1010),
+                chimeric("c", "chr1", 1020),
+            ],
+            // read names are only unique within a sample
+            vec![chimeric("a", "chr1", 1005), split("d", 1100, Flank::Left)],
+            vec![],
+        ];
+        let calls = call_joint_insertions(&evidence, &JOINT);
+        assert_eq!(calls.sites.len(), 1);
+        assert_eq!(support_of(&calls.sites[0]), (4, 1));
+        let per_sample: Vec<(u32, u32)> = calls.samples.iter().map(|s| support_of(&s[0])).collect();
+        assert_eq!(per_sample, [(3, 0), (1, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn one_sample_must_reach_min_reads() {
+        // four reads in total, but no more than two in any sample
+        let evidence = vec![
+            vec![chimeric("a", "chr1", 1000), chimeric("b", "chr1", 1010)],
+            vec![chimeric("c", "chr1", 1005), chimeric("d", "chr1", 1015)],
+        ];
+        let calls = call_joint_insertions(&evidence, &JOINT);
+        assert!(calls.sites.is_empty());
+        assert!(calls.samples.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn sorting_keeps_samples_with_their_site() {
+        let site = |chrom: &str, position: u32| {
+            vec![
+                chimeric("a", chrom, position),
+                chimeric("b", chrom, position),
+                chimeric("c", chrom, position),
+            ]
+        };
+        let evidence = vec![
+            [site("chr1", 1000), site("chr2", 5000)].concat(),
+            site("chr2", 5000)[..1].to_vec(),
+        ];
+        let mut calls = call_joint_insertions(&evidence, &JOINT);
+        calls.sort_by_contig(&[("chr2".to_string(), 10000), ("chr1".to_string(), 10000)]);
+        let chroms: Vec<&str> = calls.sites.iter().map(|i| i.chrom.as_str()).collect();
+        assert_eq!(chroms, ["chr2", "chr1"]);
+        for (i, site) in calls.sites.iter().enumerate() {
+            for insertions in &calls.samples {
+                assert_eq!(insertions[i].chrom, site.chrom);
+            }
+        }
+        assert_eq!(support_of(&calls.samples[1][0]), (1, 0));
+        assert_eq!(support_of(&calls.samples[1][1]), (0, 0));
+    }
+}
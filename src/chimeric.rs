@@ -0,0 +1,132 @@
+//! Chimeric read pairs: a uniquely mapped human anchor whose mate hits the TE library.
+//!
+//! Each pair yields one `chimeric` [`Breakpoint`] (see [`crate::breakpoint`] for the
+//! `<id>_all_breakpoint` schema) with
+//! - `chrom`, `position` and `strand` from the anchor's human alignment, `position`
+//!   being its 1-based start;
+//! - `flank` `L` for a forward anchor, which reads towards an insertion on its right,
+//!   and `R` for a reverse one;
+//! - `te_name`, `te_position` and `te_strand` from the mate's primary TE alignment.
+
+use crate::anchor::{Anchor, Mate};
+use crate::bam::{flags, Header, Record};
+use crate::breakpoint::{Breakpoint, Evidence, Flank, Strand};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io;
+
+/// Joins `anchors` with the TE alignments of their mates. TE hits scoring below
+/// `min_te_score` are ignored; hits without `AS` are kept.
+pub fn call_chimeric<I>(
+    anchors: &[Anchor],
+    te_records: I,
+    te_header: &Header,
+    min_te_score: i32,
+) -> Result<Vec<Breakpoint>>
+where
+    I: IntoIterator<Item = io::Result<Record>>,
+{
+    // keyed by mate too, as both reads of a pair may anchor
+    let by_read: HashMap<(&str, Mate), &Anchor> = anchors
+        .iter()
+        .map(|anchor| ((anchor.read_id.as_str(), anchor.mate), anchor))
+        .collect();
+    let mut breakpoints = Vec::new();
+    for record in te_records {
+        let record = record?;
+        if record.is_unmapped() || record.is_secondary_or_supplementary() {
+            continue;
+        }
+        let te_mate = if record.has_flag(flags::SECOND_IN_PAIR) {
+            Mate::Second
+        } else {
+            Mate::First
+        };
+        let Some(anchor) = by_read.get(&(record.qname.as_str(), te_mate.opposite())) else {
+            continue;
+        };
+        if record.alignment_score().is_some_and(|s| s < min_te_score) {
+            continue;
+        }
+        breakpoints.push(Breakpoint {
+            read_id: anchor.read_id.clone(),
+            evidence: Evidence::Chimeric,
+            chrom: anchor.chrom.clone(),
+            position: anchor.position,
+            strand: anchor.strand,
+            flank: match anchor.strand {
+                Strand::Forward => Flank::Left,
+                Strand::Reverse => Flank::Right,
+            },
+            te_name: te_header.reference_name(record.ref_id).to_string(),
+            te_position: (record.pos + 1) as u32,
+            te_strand: if record.is_reverse() {
+                Strand::Reverse
+            } else {
+                Strand::Forward
+            },
+        });
+    }
+    Ok(breakpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::SamReader;
+
+    fn anchor(mate: Mate, position: u32) -> Anchor {
+        Anchor {
+            read_id: "r1".to_string(),
+            mate,
+            alignment_score: 100,
+            suboptimal_score: None,
+            cigar: "100M".to_string(),
+            chrom: "chr1".to_string(),
+            position,
+            strand: Strand::Forward,
+        }
+    }
+
+    fn chimeric(anchors: &[Anchor], te_body: &str) -> Vec<Breakpoint> {
+        let sam = format!("@HD\tVN:1.6\n@SQ\tSN:HERVK\tLN:9000\n{}", te_body);
+        let reader = SamReader::new(sam.as_bytes()).unwrap();
+        let header = reader.header().clone();
+        call_chimeric(anchors, reader, &header, 30).unwrap()
+    }
+
+    const SECOND_HITS_HERVK: &str = "r1\t129\tHERVK\t500\t60\t100M\t*\t0\t0\t*\t*\tAS:i:90\n";
+
+    #[test]
+    fn te_hit_joins_the_opposite_mate() {
+        let breakpoints = chimeric(&[anchor(Mate::First, 1000)], SECOND_HITS_HERVK);
+        assert_eq!(breakpoints.len(), 1);
+        let breakpoint = &breakpoints[0];
+        assert_eq!(
+            (
+                breakpoint.chrom.as_str(),
+                breakpoint.position,
+                breakpoint.flank
+            ),
+            ("chr1", 1000, Flank::Left)
+        );
+        assert_eq!(
+            (breakpoint.te_name.as_str(), breakpoint.te_position),
+            ("HERVK", 500)
+        );
+    }
+
+    #[test]
+    fn both_mates_anchored_keeps_the_right_anchor() {
+        let anchors = [anchor(Mate::First, 1000), anchor(Mate::Second, 5000)];
+        let breakpoints = chimeric(&anchors, SECOND_HITS_HERVK);
+        assert_eq!(breakpoints.len(), 1);
+        assert_eq!(breakpoints[0].position, 1000);
+    }
+
+    #[test]
+    fn te_hit_of_the_anchor_itself_is_skipped() {
+        let breakpoints = chimeric(&[anchor(Mate::Second, 1000)], SECOND_HITS_HERVK);
+        assert!(breakpoints.is_empty());
+    }
+}
@@ -0,0 +1,350 @@
+//! Running external tools: argv vectors, native stdout and stderr redirection,
+//! and structured errors carrying the exit status and stderr tail.
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// Number of trailing stderr lines kept for error messages.
+const STDERR_TAIL: usize = 20;
+
+/// Where a child's stdout or stderr goes.
+#[derive(Debug, Clone, Default)]
+pub enum Redirect {
+    /// Our own stdout or stderr.
+    #[default]
+    Inherit,
+    /// Truncates and writes the file, like `>`.
+    File(PathBuf),
+}
+
+impl Redirect {
+    fn stdio(&self) -> io::Result<Stdio> {
+        Ok(match self {
+            Redirect::Inherit => Stdio::inherit(),
+            Redirect::File(path) => File::create(path)?.into(),
+        })
+    }
+
+    /// Where stderr lines are copied to by [`forward_stderr`].
+    fn stderr_sink(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(match self {
+            Redirect::Inherit => Box::new(io::stderr()),
+            Redirect::File(path) => Box::new(File::create(path)?),
+        })
+    }
+
+    fn path(&self) -> PathBuf {
+        match self {
+            Redirect::File(path) => path.clone(),
+            Redirect::Inherit => PathBuf::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandErrorKind {
+    /// The program could not be started.
+    Spawn(io::Error),
+    /// A redirection target could not be opened.
+    Redirect(PathBuf, io::Error),
+    /// The program ran and exited unsuccessfully.
+    Failed {
+        status: ExitStatus,
+        /// Last lines the program wrote to stderr.
+        stderr: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    /// The command line as logged.
+    pub command: String,
+    pub kind: CommandErrorKind,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            // the io::Error is reported as the source
+            CommandErrorKind::Spawn(_) => write!(f, "could not run `{}`", self.command),
+            CommandErrorKind::Redirect(path, _) => write!(
+                f,
+                "could not open {} for `{}`",
+                path.display(),
+                self.command
+            ),
+            CommandErrorKind::Failed { status, stderr } => {
+                match status.code() {
+                    Some(code) => write!(f, "`{}` exited with code {}", self.command, code)?,
+                    None => write!(f, "`{}` was terminated: {}", self.command, status)?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, "\n{}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            CommandErrorKind::Spawn(err) | CommandErrorKind::Redirect(_, err) => Some(err),
+            CommandErrorKind::Failed { .. } => None,
+        }
+    }
+}
+
+/// A program with its arguments and redirections.
+#[derive(Debug, Clone)]
+pub struct Cmd {
+    program: OsString,
+    args: Vec<OsString>,
+    stdout: Redirect,
+    stderr: Redirect,
+}
+
+impl Cmd {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Cmd {
+            program: program.as_ref().to_os_string(),
+            args: Vec::new(),
+            stdout: Redirect::Inherit,
+            stderr: Redirect::Inherit,
+        }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    pub fn stdout(mut self, redirect: Redirect) -> Self {
+        self.stdout = redirect;
+        self
+    }
+
+    /// Sends stderr to `redirect`; its last lines are still kept for errors.
+    pub fn stderr(mut self, redirect: Redirect) -> Self {
+        self.stderr = redirect;
+        self
+    }
+
+    /// Logs and runs the command, waiting for it to finish.
+    pub fn run(&self) -> Result<(), CommandError> {
+        println!("~~~~~ {}", self);
+        let mut command = self.command();
+        command.stdout(self.stdio(&self.stdout)?);
+        let (mut child, stderr) = self.spawn_capturing(command)?;
+        let status = child
+            .wait()
+            .map_err(|e| self.error(CommandErrorKind::Spawn(e)));
+        self.finish(status?, stderr)
+    }
+
+    /// Logs and runs `self | next`, with `next` reading this command's stdout.
+    pub fn pipe(&self, next: &Cmd) -> Result<(), CommandError> {
+        println!("~~~~~ {} | {}", self, next);
+        let mut command = self.command();
+        command.stdout(Stdio::piped());
+        let (mut child, stderr) = self.spawn_capturing(command)?;
+        let pipe = child.stdout.take().map_or_else(Stdio::null, Stdio::from);
+
+        let mut downstream = next.command();
+        downstream.stdin(pipe).stdout(next.stdio(&next.stdout)?);
+        let downstream = next.spawn_capturing(downstream);
+        let status = child
+            .wait()
+            .map_err(|e| self.error(CommandErrorKind::Spawn(e)));
+        let (mut next_child, next_stderr) = downstream?;
+        let next_status = next_child
+            .wait()
+            .map_err(|e| next.error(CommandErrorKind::Spawn(e)));
+        self.finish(status?, stderr)?;
+        next.finish(next_status?, next_stderr)
+    }
+
+    /// Logs and starts the command with its stdout piped back to the caller.
+    pub fn spawn_reader(&self) -> Result<(ChildStdout, Running), CommandError> {
+        println!("~~~~~ {}", self);
+        let mut command = self.command();
+        command.stdout(Stdio::piped());
+        let (mut child, stderr) = self.spawn_capturing(command)?;
+        let stdout = child.stdout.take().ok_or_else(|| {
+            self.error(CommandErrorKind::Spawn(io::Error::other(
+                "stdout was not captured",
+            )))
+        })?;
+        let running = Running {
+            cmd: self.clone(),
+            child,
+            stderr,
+        };
+        Ok((stdout, running))
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command
+    }
+
+    fn stdio(&self, redirect: &Redirect) -> Result<Stdio, CommandError> {
+        redirect
+            .stdio()
+            .map_err(|e| self.error(CommandErrorKind::Redirect(redirect.path(), e)))
+    }
+
+    /// Spawns `command` with stderr forwarded to its redirection through a
+    /// capturing thread.
+    fn spawn_capturing(
+        &self,
+        mut command: Command,
+    ) -> Result<(Child, Option<JoinHandle<String>>), CommandError> {
+        let sink = self
+            .stderr
+            .stderr_sink()
+            .map_err(|e| self.error(CommandErrorKind::Redirect(self.stderr.path(), e)))?;
+        let mut child = command
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| self.error(CommandErrorKind::Spawn(e)))?;
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| forward_stderr(stderr, sink));
+        Ok((child, stderr))
+    }
+
+    fn finish(
+        &self,
+        status: ExitStatus,
+        stderr: Option<JoinHandle<String>>,
+    ) -> Result<(), CommandError> {
+        let stderr = stderr
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default();
+        if status.success() {
+            Ok(())
+        } else {
+            Err(self.error(CommandErrorKind::Failed { status, stderr }))
+        }
+    }
+
+    fn error(&self, kind: CommandErrorKind) -> CommandError {
+        CommandError {
+            command: self.to_string(),
+            kind,
+        }
+    }
+}
+
+/// A command started by [`Cmd::spawn_reader`].
+pub struct Running {
+    cmd: Cmd,
+    child: Child,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl Running {
+    /// Waits for the command, failing if it exited unsuccessfully.
+    pub fn wait(mut self) -> Result<(), CommandError> {
+        let status = self
+            .child
+            .wait()
+            .map_err(|e| self.cmd.error(CommandErrorKind::Spawn(e)))?;
+        self.cmd.finish(status, self.stderr)
+    }
+}
+
+/// Copies a child's stderr to `sink`, returning its last [`STDERR_TAIL`] lines.
+fn forward_stderr(stderr: ChildStderr, mut sink: Box<dyn Write + Send>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL);
+        for line in BufReader::new(stderr).lines() {
+            let Ok(line) = line else { break };
+            let _ = writeln!(sink, "{}", line);
+            if tail.len() == STDERR_TAIL {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        Vec::from(tail).join("\n")
+    })
+}
+
+/// Quotes a word for display when a shell would split or expand it.
+fn quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    let plain = !word.is_empty()
+        && word
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_-+=/.,:@%".contains(&b));
+    if plain {
+        word.into_owned()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+impl fmt::Display for Cmd {
+    /// The command as a shell line, so logged commands can be rerun by hand.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&quote(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", quote(arg))?;
+        }
+        if let Redirect::File(path) = &self.stdout {
+            write!(f, " >{}", quote(path.as_os_str()))?;
+        }
+        if let Redirect::File(path) = &self.stderr {
+            write!(f, " 2>{}", quote(path.as_os_str()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    #[test]
+    fn stderr_to_file_keeps_the_tail() {
+        let dir = TempDir::new("cmd");
+        let log = dir.join("err.log");
+        let cmd = Cmd::new("sh")
+            .args(["-c", "echo oops >&2; exit 3"])
+            .stderr(Redirect::File(log.clone()));
+        let err = cmd.run().unwrap_err();
+        assert!(
+            matches!(err.kind, CommandErrorKind::Failed { ref stderr, .. } if stderr == "oops")
+        );
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "oops\n");
+        assert!(cmd.to_string().ends_with(&format!(" 2>{}", log.display())));
+    }
+
+    #[test]
+    fn spawn_error_is_reported_once() {
+        let err = Cmd::new("/nonexistent/program").run().unwrap_err();
+        assert_eq!(err.to_string(), "could not run `/nonexistent/program`");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
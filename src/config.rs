@@ -0,0 +1,476 @@
+//! Pipeline settings, merged from the command line and an optional TOML or YAML
+//! `--config` file, then resolved and validated once and handed to every stage.
+
+use crate::aligner::AlignerKind;
+use crate::anchor::AnchorParams;
+use crate::calling::CallingParams;
+use crate::genotype::GenotypeParams;
+use crate::softclip::{ClipSide, SplitReadPolicy};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum DataType {
+    #[value(name = "WGS")]
+    #[serde(rename = "WGS")]
+    Wgs,
+    #[value(name = "RNA-seq")]
+    #[serde(rename = "RNA-seq")]
+    RnaSeq,
+    #[value(name = "WES")]
+    #[serde(rename = "WES")]
+    Wes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SequencingType {
+    #[value(name = "single-end")]
+    SingleEnd,
+    #[value(name = "paired-end")]
+    PairedEnd,
+    /// Paired-end FASTQ with both mates in one file.
+    Interleaved,
+}
+
+impl fmt::Display for SequencingType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SequencingType {
+    /// Whether reads come in pairs, which holds for aligned interleaved input too.
+    pub fn paired(self) -> bool {
+        self != SequencingType::SingleEnd
+    }
+}
+
+/// Every setting a run can take, each optional so that the command line and a
+/// config file can be layered. Keys are the same in TOML and YAML files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub sample_id: Option<String>,
+    pub file_suffix: Option<String>,
+    pub input_directory: Option<PathBuf>,
+    pub output_directory: Option<PathBuf>,
+    pub bam: Option<PathBuf>,
+    pub fq1: Option<PathBuf>,
+    pub fq2: Option<PathBuf>,
+    pub sample_sheet: Option<PathBuf>,
+    pub human_reference: Option<String>,
+    pub te_reference: Option<String>,
+    pub data_type: Option<DataType>,
+    pub sequencing_type: Option<SequencingType>,
+    pub insert_size: Option<f32>,
+    pub insert_size_sd: Option<f32>,
+    pub read_len: Option<u32>,
+    pub threads: Option<u32>,
+    pub min_reads: Option<u32>,
+    pub multiple_bam: Option<bool>,
+    pub bwa_aligned_input: Option<bool>,
+    pub human_aligner: Option<AlignerKind>,
+    pub te_aligner: Option<AlignerKind>,
+    pub chimeric_te_min_score: Option<u32>,
+    pub split_te_min_score: Option<u32>,
+    pub min_alignment_score: Option<i32>,
+    pub min_as_xs_ratio: Option<f64>,
+    pub split_reads: Option<bool>,
+    pub min_clip_len: Option<u32>,
+    pub clip_side: Option<ClipSide>,
+    pub min_clip_quality: Option<f64>,
+    pub max_clip_base_fraction: Option<f64>,
+    pub tsd_window: Option<u32>,
+    pub genotype: Option<bool>,
+    pub genotype_error_rate: Option<f64>,
+    pub genotype_min_flank: Option<u32>,
+}
+
+impl Settings {
+    /// Reads a config file, choosing TOML or YAML by its extension. Relative paths
+    /// in the file are taken from the file's directory.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let settings = match extension {
+            Some("toml") => toml::from_str(&text).map_err(anyhow::Error::from),
+            Some("yaml" | "yml") => serde_norway::from_str(&text).map_err(anyhow::Error::from),
+            _ => Err(anyhow!("the file name should end in .toml, .yaml or .yml")),
+        };
+        let settings: Settings =
+            settings.with_context(|| format!("invalid config file {}", path.display()))?;
+        Ok(settings.relative_to(path.parent().unwrap_or(Path::new(""))))
+    }
+
+    /// Joins the relative paths among the settings onto `base`.
+    fn relative_to(self, base: &Path) -> Settings {
+        let join = |path: Option<PathBuf>| path.map(|path| base.join(path));
+        let join_str =
+            |path: Option<String>| path.map(|path| base.join(path).to_string_lossy().into_owned());
+        Settings {
+            input_directory: join(self.input_directory),
+            output_directory: join(self.output_directory),
+            bam: join(self.bam),
+            fq1: join(self.fq1),
+            fq2: join(self.fq2),
+            sample_sheet: join(self.sample_sheet),
+            human_reference: join_str(self.human_reference),
+            te_reference: join_str(self.te_reference),
+            ..self
+        }
+    }
+
+    /// Takes each setting from `self`, falling back to `other` where it is unset.
+    pub fn or(self, other: Settings) -> Settings {
+        Settings {
+            sample_id: self.sample_id.or(other.sample_id),
+            file_suffix: self.file_suffix.or(other.file_suffix),
+            input_directory: self.input_directory.or(other.input_directory),
+            output_directory: self.output_directory.or(other.output_directory),
+            bam: self.bam.or(other.bam),
+            fq1: self.fq1.or(other.fq1),
+            fq2: self.fq2.or(other.fq2),
+            sample_sheet: self.sample_sheet.or(other.sample_sheet),
+            human_reference: self.human_reference.or(other.human_reference),
+            te_reference: self.te_reference.or(other.te_reference),
+            data_type: self.data_type.or(other.data_type),
+            sequencing_type: self.sequencing_type.or(other.sequencing_type),
+            insert_size: self.insert_size.or(other.insert_size),
+            insert_size_sd: self.insert_size_sd.or(other.insert_size_sd),
+            read_len: self.read_len.or(other.read_len),
+            threads: self.threads.or(other.threads),
+            min_reads: self.min_reads.or(other.min_reads),
+            multiple_bam: self.multiple_bam.or(other.multiple_bam),
+            bwa_aligned_input: self.bwa_aligned_input.or(other.bwa_aligned_input),
+            human_aligner: self.human_aligner.or(other.human_aligner),
+            te_aligner: self.te_aligner.or(other.te_aligner),
+            chimeric_te_min_score: self.chimeric_te_min_score.or(other.chimeric_te_min_score),
+            split_te_min_score: self.split_te_min_score.or(other.split_te_min_score),
+            min_alignment_score: self.min_alignment_score.or(other.min_alignment_score),
+            min_as_xs_ratio: self.min_as_xs_ratio.or(other.min_as_xs_ratio),
+            split_reads: self.split_reads.or(other.split_reads),
+            min_clip_len: self.min_clip_len.or(other.min_clip_len),
+            clip_side: self.clip_side.or(other.clip_side),
+            min_clip_quality: self.min_clip_quality.or(other.min_clip_quality),
+            max_clip_base_fraction: self.max_clip_base_fraction.or(other.max_clip_base_fraction),
+            tsd_window: self.tsd_window.or(other.tsd_window),
+            genotype: self.genotype.or(other.genotype),
+            genotype_error_rate: self.genotype_error_rate.or(other.genotype_error_rate),
+            genotype_min_flank: self.genotype_min_flank.or(other.genotype_min_flank),
+        }
+    }
+
+    /// Fills in defaults and checks the settings every stage relies on.
+    pub fn resolve(self) -> Result<PipelineConfig> {
+        let required = |value: Option<String>, what: &str| {
+            value
+                .filter(|v| !v.is_empty())
+                .ok_or_else(|| anyhow!("no {} was given on the command line or in --config", what))
+        };
+        // input paths are made absolute, as the run changes into the output directory
+        let current_dir = env::current_dir().context("could not get the current directory")?;
+        let absolute = |path: Option<PathBuf>| path.map(|path| current_dir.join(path));
+        let absolute_reference =
+            |reference: String| current_dir.join(reference).to_string_lossy().into_owned();
+        if self.fq2.is_some() && self.fq1.is_none() {
+            return Err(anyhow!("fq2 was given without fq1"));
+        }
+        let threads = self.threads.unwrap_or(1);
+        if threads == 0 {
+            return Err(anyhow!("threads must be at least 1"));
+        }
+        let min_reads = self.min_reads.unwrap_or(3);
+        if min_reads == 0 {
+            return Err(anyhow!("min_reads must be at least 1"));
+        }
+        let max_base_fraction = self.max_clip_base_fraction.unwrap_or(0.9);
+        if !(0.0..=1.0).contains(&max_base_fraction) {
+            return Err(anyhow!("max_clip_base_fraction must be between 0 and 1"));
+        }
+        let error_rate = self.genotype_error_rate.unwrap_or(0.05);
+        if !(error_rate > 0.0 && error_rate < 0.5) {
+            return Err(anyhow!("genotype_error_rate must be between 0 and 0.5"));
+        }
+        let sequencing_type = self.sequencing_type.unwrap_or(SequencingType::PairedEnd);
+        let paired = sequencing_type.paired();
+        let insert_size = match self.insert_size {
+            Some(size) if paired && size <= 0.0 => {
+                return Err(anyhow!("insert_size must be positive"));
+            }
+            Some(size) if paired => size,
+            _ => 500.0,
+        };
+        let bwa_aligned_input = self.bwa_aligned_input.unwrap_or(false);
+        let min_clip_len = self.min_clip_len.unwrap_or(20);
+        Ok(PipelineConfig {
+            sample_id: required(self.sample_id, "sample (-i)")?,
+            file_suffix: self.file_suffix.unwrap_or_else(|| "bam".to_string()),
+            input_directory: current_dir.join(self.input_directory.unwrap_or_default()),
+            output_directory: current_dir.join(self.output_directory.unwrap_or_default()),
+            bam: absolute(self.bam),
+            fq1: absolute(self.fq1),
+            fq2: absolute(self.fq2),
+            sample_sheet: absolute(self.sample_sheet),
+            human_reference: absolute_reference(required(
+                self.human_reference,
+                "human reference (-H)",
+            )?),
+            te_reference: absolute_reference(required(self.te_reference, "TE reference (-T)")?),
+            data_type: self.data_type.unwrap_or(DataType::Wgs),
+            sequencing_type,
+            insert_size,
+            insert_size_sd: self.insert_size_sd,
+            threads,
+            multiple_bam: self.multiple_bam.unwrap_or(false),
+            bwa_aligned_input,
+            human_aligner: self.human_aligner.unwrap_or(if bwa_aligned_input {
+                AlignerKind::Bwa
+            } else {
+                AlignerKind::Bowtie2
+            }),
+            te_aligner: self.te_aligner.unwrap_or(AlignerKind::Bwa),
+            split_reads: self.split_reads.unwrap_or(true) || !paired,
+            chimeric_te_min_score: self.chimeric_te_min_score.unwrap_or(30),
+            split_te_min_score: self.split_te_min_score.unwrap_or(20),
+            anchor: AnchorParams {
+                min_alignment_score: self.min_alignment_score.unwrap_or(30),
+                min_as_xs_ratio: self.min_as_xs_ratio.unwrap_or(2.0),
+            },
+            split: SplitReadPolicy {
+                side: self.clip_side,
+                min_clip_len: min_clip_len as usize,
+                min_mean_quality: self.min_clip_quality.unwrap_or(0.0),
+                max_base_fraction,
+                proper_pair: paired,
+            },
+            calling: CallingParams {
+                // twice the insert size keeps both flanks of an insertion in one cluster
+                window: (2.0 * insert_size) as u32,
+                tsd_window: self.tsd_window.unwrap_or(100),
+                min_reads,
+                read_len: self.read_len.unwrap_or(100),
+            },
+            genotype: self.genotype.unwrap_or(false).then_some(GenotypeParams {
+                min_flank: self.genotype_min_flank.unwrap_or(20),
+                error_rate,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub sample_id: String,
+    pub file_suffix: String,
+    pub input_directory: PathBuf,
+    pub output_directory: PathBuf,
+    /// Explicit inputs, taking precedence over `input_directory` naming; see
+    /// [`crate::input`].
+    pub bam: Option<PathBuf>,
+    pub fq1: Option<PathBuf>,
+    pub fq2: Option<PathBuf>,
+    pub sample_sheet: Option<PathBuf>,
+    pub human_reference: String,
+    pub te_reference: String,
+    pub data_type: DataType,
+    pub sequencing_type: SequencingType,
+    /// Mean library insert size.
+    pub insert_size: f32,
+    pub insert_size_sd: Option<f32>,
+    pub threads: u32,
+    /// The input is a file listing several BAM files.
+    pub multiple_bam: bool,
+    /// The input BAM was aligned with bwa mem, so its reads are used without
+    /// realignment to the human reference.
+    pub bwa_aligned_input: bool,
+    pub human_aligner: AlignerKind,
+    pub te_aligner: AlignerKind,
+    /// Collect split-read evidence: on unless `split_reads = false` is set in the
+    /// config file, and always on for single-end data.
+    pub split_reads: bool,
+    /// Minimum score of a TE library hit for the mates of discordant pairs.
+    pub chimeric_te_min_score: u32,
+    /// Minimum score of a TE library hit for soft-clipped segments.
+    pub split_te_min_score: u32,
+    pub anchor: AnchorParams,
+    pub split: SplitReadPolicy,
+    pub calling: CallingParams,
+    /// Set when genotyping was requested.
+    pub genotype: Option<GenotypeParams>,
+}
+
+impl PipelineConfig {
+    pub fn paired(&self) -> bool {
+        self.sequencing_type.paired()
+    }
+
+    /// The settings this configuration was resolved from, with every default
+    /// spelled out, so that loading them again gives the same configuration.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            sample_id: Some(self.sample_id.clone()),
+            file_suffix: Some(self.file_suffix.clone()),
+            input_directory: Some(self.input_directory.clone()),
+            output_directory: Some(self.output_directory.clone()),
+            bam: self.bam.clone(),
+            fq1: self.fq1.clone(),
+            fq2: self.fq2.clone(),
+            sample_sheet: self.sample_sheet.clone(),
+            human_reference: Some(self.human_reference.clone()),
+            te_reference: Some(self.te_reference.clone()),
+            data_type: Some(self.data_type),
+            sequencing_type: Some(self.sequencing_type),
+            insert_size: Some(self.insert_size),
+            insert_size_sd: self.insert_size_sd,
+            read_len: Some(self.calling.read_len),
+            threads: Some(self.threads),
+            min_reads: Some(self.calling.min_reads),
+            multiple_bam: Some(self.multiple_bam),
+            bwa_aligned_input: Some(self.bwa_aligned_input),
+            human_aligner: Some(self.human_aligner),
+            te_aligner: Some(self.te_aligner),
+            chimeric_te_min_score: Some(self.chimeric_te_min_score),
+            split_te_min_score: Some(self.split_te_min_score),
+            min_alignment_score: Some(self.anchor.min_alignment_score),
+            min_as_xs_ratio: Some(self.anchor.min_as_xs_ratio),
+            split_reads: Some(self.split_reads),
+            min_clip_len: Some(self.split.min_clip_len as u32),
+            clip_side: self.split.side,
+            min_clip_quality: Some(self.split.min_mean_quality),
+            max_clip_base_fraction: Some(self.split.max_base_fraction),
+            tsd_window: Some(self.calling.tsd_window),
+            genotype: Some(self.genotype.is_some()),
+            genotype_error_rate: Some(self.genotype.as_ref().map_or(0.05, |g| g.error_rate)),
+            genotype_min_flank: Some(self.genotype.as_ref().map_or(20, |g| g.min_flank)),
+        }
+    }
+
+    /// Writes the effective settings as TOML, which `--config` accepts.
+    pub fn write_toml<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let text = toml::to_string(&self.settings())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        out.write_all(text.as_bytes())
+    }
+
+    /// Writes the effective settings under a comment naming the program version,
+    /// so that a run's results can be traced back to, and rerun with, its parameters.
+    pub fn write_metadata<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "# ervcaller-rs {}", env!("CARGO_PKG_VERSION"))?;
+        self.write_toml(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    fn minimal() -> Settings {
+        Settings {
+            sample_id: Some("s1".to_string()),
+            human_reference: Some("hg38.fa".to_string()),
+            te_reference: Some("te.fa".to_string()),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn genotype_error_rate_is_checked() {
+        for rate in [0.0, 0.5, -0.1, f64::NAN] {
+            let settings = Settings {
+                genotype_error_rate: Some(rate),
+                ..minimal()
+            };
+            assert!(settings.resolve().is_err(), "{} was accepted", rate);
+        }
+        let settings = Settings {
+            genotype: Some(true),
+            genotype_error_rate: Some(0.01),
+            ..minimal()
+        };
+        assert_eq!(
+            settings.resolve().unwrap().genotype.unwrap().error_rate,
+            0.01
+        );
+    }
+
+    #[test]
+    fn genotype_flank_is_its_own_setting() {
+        let resolve = |settings: Settings| {
+            Settings {
+                genotype: Some(true),
+                min_clip_len: Some(30),
+                ..settings
+            }
+            .resolve()
+            .unwrap()
+        };
+        let config = resolve(minimal());
+        assert_eq!(config.genotype.as_ref().unwrap().min_flank, 20);
+        let config = resolve(Settings {
+            genotype_min_flank: Some(10),
+            ..minimal()
+        });
+        assert_eq!(config.genotype.as_ref().unwrap().min_flank, 10);
+        assert_eq!(config.settings().genotype_min_flank, Some(10));
+    }
+
+    #[test]
+    fn split_reads_are_on_by_default() {
+        assert!(minimal().resolve().unwrap().split_reads);
+        let off = |sequencing_type| {
+            Settings {
+                split_reads: Some(false),
+                sequencing_type: Some(sequencing_type),
+                ..minimal()
+            }
+            .resolve()
+            .unwrap()
+            .split_reads
+        };
+        assert!(!off(SequencingType::PairedEnd));
+        assert!(off(SequencingType::SingleEnd));
+    }
+
+    #[test]
+    fn references_are_made_absolute() {
+        let config = minimal().resolve().unwrap();
+        let current_dir = env::current_dir().unwrap();
+        assert_eq!(
+            Path::new(&config.human_reference),
+            current_dir.join("hg38.fa")
+        );
+        assert_eq!(Path::new(&config.te_reference), current_dir.join("te.fa"));
+    }
+
+    #[test]
+    fn config_file_paths_are_relative_to_the_file() {
+        let dir = TempDir::with_files(
+            "config",
+            &[(
+                "run.toml",
+                "sample_id = \"s1\"\nfq1 = \"reads/s1_1.fq\"\nhuman_reference = \"/ref/hg38.fa\"\nte_reference = \"te.fa\"\n",
+            )],
+        );
+        let settings = Settings::load(dir.join("run.toml")).unwrap();
+        assert_eq!(settings.sample_id.as_deref(), Some("s1"));
+        assert_eq!(settings.fq1, Some(dir.join("reads/s1_1.fq")));
+        assert_eq!(settings.human_reference.as_deref(), Some("/ref/hg38.fa"));
+        assert_eq!(
+            settings.te_reference,
+            Some(dir.join("te.fa").to_string_lossy().into_owned())
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Random access to a FASTA file through its samtools `.fai` index.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+struct FaiEntry {
+    length: u64,
+    offset: u64,
+    line_bases: u64,
+    line_width: u64,
+}
+
+pub struct IndexedFasta {
+    file: File,
+    entries: HashMap<String, FaiEntry>,
+    /// Sequence names and lengths in file order.
+    contigs: Vec<(String, u64)>,
+}
+
+impl IndexedFasta {
+    /// Opens `path` together with `<path>.fai`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let fai_path = format!("{}.fai", path.display());
+        let fai = fs::read_to_string(&fai_path)
+            .with_context(|| format!("could not read {}", fai_path))?;
+        let mut entries = HashMap::new();
+        let mut contigs = Vec::new();
+        for (n, line) in fai.lines().enumerate() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 5 {
+                return Err(anyhow!("{}:{}: malformed index line", fai_path, n + 1));
+            }
+            let number = |i: usize| -> Result<u64> {
+                fields[i]
+                    .parse()
+                    .with_context(|| format!("{}:{}: malformed index line", fai_path, n + 1))
+            };
+            let entry = FaiEntry {
+                length: number(1)?,
+                offset: number(2)?,
+                line_bases: number(3)?,
+                line_width: number(4)?,
+            };
+            contigs.push((fields[0].to_string(), entry.length));
+            entries.insert(fields[0].to_string(), entry);
+        }
+        let file =
+            File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        Ok(IndexedFasta {
+            file,
+            entries,
+            contigs,
+        })
+    }
+
+    pub fn contigs(&self) -> &[(String, u64)] {
+        &self.contigs
+    }
+
+    /// The upper-cased base at 1-based `position` of `chrom`, if it exists.
+    pub fn base(&mut self, chrom: &str, position: u64) -> Result<Option<u8>> {
+        let Some(entry) = self.entries.get(chrom) else {
+            return Ok(None);
+        };
+        if position == 0 || position > entry.length || entry.line_bases == 0 {
+            return Ok(None);
+        }
+        let p = position - 1;
+        let offset =
+            entry.offset + (p / entry.line_bases) * entry.line_width + p % entry.line_bases;
+        let mut base = [0u8; 1];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut base)?;
+        Ok(Some(base[0].to_ascii_uppercase()))
+    }
+}
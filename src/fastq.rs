@@ -0,0 +1,330 @@
+//! FASTQ records with a validating streaming reader and a writer.
+//!
+//! Gzip and BGZF input is recognised from its magic bytes and decompressed on the
+//! fly; output paths ending in `.gz` or `.bgz` are written as BGZF, which any gzip
+//! reader accepts.
+
+use crate::bam::Record;
+use crate::bgzf::BgzfWriter;
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FastqRecord {
+    /// The header line without the leading `@`.
+    pub name: String,
+    pub seq: Vec<u8>,
+    /// Phred+33 encoded qualities, one per base.
+    pub qual: Vec<u8>,
+}
+
+impl FastqRecord {
+    /// An alignment record in its original sequencing orientation.
+    pub fn from_alignment(record: &Record) -> Self {
+        let (seq, qual) = if record.is_reverse() {
+            (
+                reverse_complement(&record.seq),
+                record.qual.iter().rev().copied().collect(),
+            )
+        } else {
+            (record.seq.clone(), record.qual.clone())
+        };
+        FastqRecord {
+            name: record.qname.clone(),
+            qual: phred_to_ascii(&qual, seq.len()),
+            seq,
+        }
+    }
+
+    /// The first word of the name without a `/1` or `/2` mate suffix.
+    pub fn read_id(&self) -> &str {
+        let id = self.name.split_ascii_whitespace().next().unwrap_or("");
+        id.strip_suffix("/1")
+            .or_else(|| id.strip_suffix("/2"))
+            .unwrap_or(id)
+    }
+}
+
+/// Converts raw phred scores to FASTQ characters, substituting `I` for missing qualities.
+pub fn phred_to_ascii(qual: &[u8], len: usize) -> Vec<u8> {
+    if qual.len() != len || qual.first() == Some(&0xff) {
+        return vec![b'I'; len];
+    }
+    qual.iter().map(|q| q.saturating_add(33)).collect()
+}
+
+fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' => b'a',
+            other => *other,
+        })
+        .collect()
+}
+
+/// Reads FASTQ records one at a time, checking the four-line structure. Errors
+/// name the source and line they occurred on.
+pub struct FastqReader<R> {
+    inner: R,
+    source: String,
+    line_no: u64,
+    line: Vec<u8>,
+}
+
+impl FastqReader<Box<dyn BufRead>> {
+    /// Opens a plain, gzip or BGZF compressed FASTQ file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let source = path.display().to_string();
+        let context = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", source, e));
+        let mut file = BufReader::new(File::open(path).map_err(context)?);
+        let gzipped = file.fill_buf().map_err(context)?.starts_with(&[0x1f, 0x8b]);
+        let inner: Box<dyn BufRead> = if gzipped {
+            Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        } else {
+            Box::new(file)
+        };
+        Ok(FastqReader::new(inner, source))
+    }
+}
+
+impl<R: BufRead> FastqReader<R> {
+    /// Reads from `inner`; `source` names it in error messages.
+    pub fn new(inner: R, source: String) -> Self {
+        FastqReader {
+            inner,
+            source,
+            line_no: 0,
+            line: Vec::new(),
+        }
+    }
+
+    fn error(&self, kind: io::ErrorKind, message: &str) -> io::Error {
+        io::Error::new(
+            kind,
+            format!("{}:{}: {}", self.source, self.line_no, message),
+        )
+    }
+
+    /// Reads the next line without its line ending, returning false at the end of input.
+    fn next_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        let n = self
+            .inner
+            .read_until(b'\n', &mut self.line)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.source, e)))?;
+        if n == 0 {
+            return Ok(false);
+        }
+        self.line_no += 1;
+        while matches!(self.line.last(), Some(b'\n' | b'\r')) {
+            self.line.pop();
+        }
+        Ok(true)
+    }
+
+    /// Reads one more line of the current record, failing at the end of input.
+    fn record_line(&mut self) -> io::Result<()> {
+        if self.next_line()? {
+            Ok(())
+        } else {
+            Err(self.error(io::ErrorKind::UnexpectedEof, "truncated FASTQ record"))
+        }
+    }
+
+    /// Reads the next record, or `None` at the end of input. Blank lines between
+    /// records are skipped.
+    pub fn read_record(&mut self) -> io::Result<Option<FastqRecord>> {
+        loop {
+            if !self.next_line()? {
+                return Ok(None);
+            }
+            if !self.line.is_empty() {
+                break;
+            }
+        }
+        let name = match self.line.strip_prefix(b"@") {
+            Some(name) => String::from_utf8(name.to_vec())
+                .map_err(|_| self.error(io::ErrorKind::InvalidData, "header is not UTF-8"))?,
+            None => {
+                return Err(self.error(io::ErrorKind::InvalidData, "expected a '@' header line"))
+            }
+        };
+        self.record_line()?;
+        let seq = self.line.clone();
+        self.record_line()?;
+        if !self.line.starts_with(b"+") {
+            return Err(self.error(io::ErrorKind::InvalidData, "expected a '+' separator line"));
+        }
+        self.record_line()?;
+        if self.line.len() != seq.len() {
+            return Err(self.error(
+                io::ErrorKind::InvalidData,
+                &format!(
+                    "{} qualities for {} bases in '{}'",
+                    self.line.len(),
+                    seq.len(),
+                    name
+                ),
+            ));
+        }
+        Ok(Some(FastqRecord {
+            name,
+            seq,
+            qual: self.line.clone(),
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for FastqReader<R> {
+    type Item = io::Result<FastqRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    Bgzf(BgzfWriter<BufWriter<File>>),
+}
+
+/// Writes FASTQ records to a file; call [`FastqWriter::finish`] to complete it.
+pub struct FastqWriter {
+    sink: Sink,
+}
+
+impl FastqWriter {
+    /// Creates `path`, BGZF compressed when it ends in `.gz` or `.bgz`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = BufWriter::new(
+            File::create(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?,
+        );
+        let compressed = path
+            .extension()
+            .is_some_and(|ext| ext == "gz" || ext == "bgz");
+        let sink = if compressed {
+            Sink::Bgzf(BgzfWriter::new(file))
+        } else {
+            Sink::Plain(file)
+        };
+        Ok(FastqWriter { sink })
+    }
+
+    pub fn write_record(&mut self, record: &FastqRecord) -> io::Result<()> {
+        let out: &mut dyn Write = match &mut self.sink {
+            Sink::Plain(out) => out,
+            Sink::Bgzf(out) => out,
+        };
+        out.write_all(b"@")?;
+        out.write_all(record.name.as_bytes())?;
+        out.write_all(b"\n")?;
+        out.write_all(&record.seq)?;
+        out.write_all(b"\n+\n")?;
+        out.write_all(&record.qual)?;
+        out.write_all(b"\n")
+    }
+
+    /// Flushes buffered records and, for compressed output, writes the EOF block.
+    pub fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::Plain(mut out) => out.flush(),
+            Sink::Bgzf(out) => out.finish()?.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    fn parse(text: &str) -> io::Result<Vec<FastqRecord>> {
+        FastqReader::new(text.as_bytes(), "test.fq".to_string()).collect()
+    }
+
+    fn error(text: &str) -> io::Error {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn reads_records_across_blank_lines() {
+        let records = parse("@r1/1 x\nACGT\n+\nIIII\n\n@r2\nAC\n+r2\n##\n").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].read_id(), "r1");
+        assert_eq!(records[1].qual, b"##");
+    }
+
+    #[test]
+    fn missing_header() {
+        let err = error("r1\nACGT\n+\nIIII\n");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "test.fq:1: expected a '@' header line");
+    }
+
+    #[test]
+    fn missing_separator() {
+        let err = error("@r1\nACGT\nIIII\nIIII\n");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "test.fq:3: expected a '+' separator line");
+    }
+
+    #[test]
+    fn quality_length_mismatch() {
+        let err = error("@r1\nACGT\n+\nIII\n");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "test.fq:4: 3 qualities for 4 bases in 'r1'"
+        );
+    }
+
+    #[test]
+    fn truncated_record() {
+        let err = error("@r1\nACGT\n+\nIIII\n@r2\nACGT\n");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.to_string(), "test.fq:6: truncated FASTQ record");
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let dir = TempDir::new("fastq");
+        let path = dir.join("reads.fq.gz");
+        let records = vec![
+            FastqRecord {
+                name: "r1/1".to_string(),
+                seq: b"ACGTN".to_vec(),
+                qual: b"IIII#".to_vec(),
+            },
+            FastqRecord {
+                name: "r1/2".to_string(),
+                seq: b"TTGCA".to_vec(),
+                qual: b"55555".to_vec(),
+            },
+        ];
+        let mut writer = FastqWriter::create(&path).unwrap();
+        for record in &records {
+            writer.write_record(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert!(std::fs::read(&path).unwrap().starts_with(&[0x1f, 0x8b]));
+        let read: Vec<FastqRecord> = FastqReader::open(&path)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read, records);
+    }
+}
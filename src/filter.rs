@@ -0,0 +1,147 @@
+//! Reduces the FASTQ intermediates to the reads that support an insertion, so
+//! that `<id>_1.1fq`/`<id>_2.1fq` keep the chimeric pairs and `<id>_1sf.fastq`
+//! the soft-clipped segments that hit the TE library.
+
+use crate::breakpoint::{Breakpoint, Evidence};
+use crate::fastq::{FastqReader, FastqRecord, FastqWriter};
+use crate::softclip::SoftClip;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// Filters the FASTQ intermediates of `sample_id` in the working directory.
+/// Files that were not produced for this run, such as `_2.1fq` for single-end
+/// data, are skipped.
+pub fn filter_intermediates(sample_id: &str, evidence: &[Breakpoint]) -> Result<()> {
+    let reads_with = |kind: Evidence| -> HashSet<&str> {
+        evidence
+            .iter()
+            .filter(|b| b.evidence == kind)
+            .map(|b| b.read_id.as_str())
+            .collect()
+    };
+    let chimeric = reads_with(Evidence::Chimeric);
+    let split = reads_with(Evidence::Split);
+
+    for suffix in ["_1.1fq", "_2.1fq"] {
+        let path = format!("{}{}", sample_id, suffix);
+        if Path::new(&path).exists() {
+            let (kept, total) = filter_fastq(&path, |record| chimeric.contains(record.read_id()))?;
+            println!("~~~~~ kept {} of {} reads in {}", kept, total, path);
+        }
+    }
+    let path = format!("{}_1sf.fastq", sample_id);
+    if Path::new(&path).exists() {
+        let (kept, total) = filter_fastq(&path, |record| {
+            record
+                .name
+                .parse::<SoftClip>()
+                .is_ok_and(|clip| split.contains(clip.read_id.as_str()))
+        })?;
+        println!(
+            "~~~~~ kept {} of {} soft-clipped segments in {}",
+            kept, total, path
+        );
+    }
+    Ok(())
+}
+
+/// Rewrites the FASTQ at `path` with the records satisfying `keep`, returning
+/// the number of records kept and read.
+pub fn filter_fastq<F: Fn(&FastqRecord) -> bool>(path: &str, keep: F) -> Result<(usize, usize)> {
+    let tmp = format!("{}.tmp", path);
+    let mut writer = FastqWriter::create(&tmp)?;
+    let (mut kept, mut total) = (0, 0);
+    for record in FastqReader::open(path)? {
+        let record = record?;
+        total += 1;
+        if keep(&record) {
+            kept += 1;
+            writer.write_record(&record)?;
+        }
+    }
+    writer.finish()?;
+    fs::rename(&tmp, path).with_context(|| format!("could not replace {}", path))?;
+    Ok((kept, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breakpoint::{Flank, Strand};
+    use crate::testdir::TempDir;
+
+    fn fastq(names: &[&str]) -> String {
+        names
+            .iter()
+            .map(|name| format!("@{}\nACGT\n+\nIIII\n", name))
+            .collect()
+    }
+
+    fn names(path: &Path) -> Vec<String> {
+        FastqReader::open(path)
+            .unwrap()
+            .map(|record| record.unwrap().name)
+            .collect()
+    }
+
+    fn evidence(read_id: &str, evidence: Evidence) -> Breakpoint {
+        Breakpoint {
+            read_id: read_id.to_string(),
+            evidence,
+            chrom: "chr1".to_string(),
+            position: 1000,
+            strand: Strand::Forward,
+            flank: Flank::Right,
+            te_name: "HERVK".to_string(),
+            te_position: 1,
+            te_strand: Strand::Forward,
+        }
+    }
+
+    #[test]
+    fn records_are_filtered_in_place() {
+        let dir = TempDir::with_files("filter-fastq", &[("a.fastq", &fastq(&["r1", "r2", "r3"]))]);
+        let path = dir.join("a.fastq");
+        let (kept, total) = filter_fastq(path.to_str().unwrap(), |r| r.name != "r2").unwrap();
+        assert_eq!((kept, total), (2, 3));
+        assert_eq!(names(&path), ["r1", "r3"]);
+        assert!(!dir.join("a.fastq.tmp").exists());
+    }
+
+    #[test]
+    fn intermediates_keep_supporting_reads() {
+        let clip = |read_id: &str| format!("soft|{}|99|chr1|1000|L|30S70M", read_id);
+        let dir = TempDir::with_files(
+            "filter-intermediates",
+            &[
+                ("s_1.1fq", &fastq(&["c1/1", "c2/1", "x1/1", "s1/1"])),
+                ("s_2.1fq", &fastq(&["c1/2", "c2/2", "x1/2", "s1/2"])),
+                (
+                    "s_1sf.fastq",
+                    &fastq(&[&clip("s1"), &clip("c1"), &clip("s2"), "s1"]),
+                ),
+            ],
+        );
+        let evidence = [
+            evidence("c1", Evidence::Chimeric),
+            evidence("c2", Evidence::Chimeric),
+            evidence("s1", Evidence::Split),
+            evidence("s2", Evidence::Split),
+        ];
+        filter_intermediates(dir.join("s").to_str().unwrap(), &evidence).unwrap();
+        assert_eq!(names(&dir.join("s_1.1fq")), ["c1/1", "c2/1"]);
+        assert_eq!(names(&dir.join("s_2.1fq")), ["c1/2", "c2/2"]);
+        assert_eq!(names(&dir.join("s_1sf.fastq")), [clip("s1"), clip("s2")]);
+    }
+
+    #[test]
+    fn missing_intermediates_are_skipped() {
+        let dir = TempDir::with_files("filter-single", &[("s_1.1fq", &fastq(&["c1", "x1"]))]);
+        let evidence = [evidence("c1", Evidence::Chimeric)];
+        filter_intermediates(dir.join("s").to_str().unwrap(), &evidence).unwrap();
+        assert_eq!(names(&dir.join("s_1.1fq")), ["c1"]);
+        assert!(!dir.join("s_2.1fq").exists());
+    }
+}
@@ -0,0 +1,250 @@
+//! Genotyping of candidate insertions against the indexed input BAM.
+
+use crate::bai::BamIndex;
+use crate::bam::{flags, BamReader};
+use crate::calling::Insertion;
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+/// Highest reported genotype quality.
+const MAX_GQ: u32 = 99;
+
+#[derive(Debug, Clone)]
+pub struct GenotypeParams {
+    /// Bases a read must align on both sides of the insertion site to count as
+    /// reference support; reads carrying the insertion would be clipped there.
+    pub min_flank: u32,
+    /// Probability that a read supports the wrong allele.
+    pub error_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Call {
+    HomRef,
+    Het,
+    HomAlt,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Call::HomRef => "0/0",
+            Call::Het => "0/1",
+            Call::HomAlt => "1/1",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Genotype {
+    pub call: Call,
+    pub ref_reads: u32,
+    pub alt_reads: u32,
+    /// Phred-scaled likelihoods of 0/0, 0/1 and 1/1, normalised to the best call.
+    pub pl: [u32; 3],
+    pub gq: u32,
+}
+
+impl Genotype {
+    /// Genotypes from read counts with a binomial model where the expected fraction
+    /// of insertion reads is `error_rate`, 0.5 and `1 - error_rate`. Without any
+    /// reads the genotype is missing.
+    pub fn from_counts(ref_reads: u32, alt_reads: u32, error_rate: f64) -> Option<Genotype> {
+        if ref_reads == 0 && alt_reads == 0 {
+            return None;
+        }
+        let log_likelihood = |alt_fraction: f64| {
+            f64::from(alt_reads) * alt_fraction.log10()
+                + f64::from(ref_reads) * (1.0 - alt_fraction).log10()
+        };
+        let likelihoods = [
+            log_likelihood(error_rate),
+            log_likelihood(0.5),
+            log_likelihood(1.0 - error_rate),
+        ];
+        let best = likelihoods.iter().copied().fold(f64::MIN, f64::max);
+        let pl = likelihoods.map(|l| (-10.0 * (l - best)).round() as u32);
+
+        let call = match pl.iter().position(|&p| p == 0) {
+            Some(0) => Call::HomRef,
+            Some(1) => Call::Het,
+            _ => Call::HomAlt,
+        };
+        let mut sorted = pl;
+        sorted.sort_unstable();
+        Some(Genotype {
+            call,
+            ref_reads,
+            alt_reads,
+            pl,
+            gq: sorted[1].min(MAX_GQ),
+        })
+    }
+}
+
+/// Counts reference-supporting reads at every insertion in the indexed `bam` and
+/// fills in `Insertion::genotype`. Insertions without reads, or on a contig the
+/// BAM does not have, are left uncalled.
+pub fn genotype_insertions(
+    bam: &Path,
+    index: &Path,
+    insertions: &mut [Insertion],
+    params: &GenotypeParams,
+) -> Result<()> {
+    let index = BamIndex::from_path(index)?;
+    let mut reader = BamReader::from_path(bam)?;
+    let header = reader.header().clone();
+
+    let mut missing_contigs = BTreeSet::new();
+    for insertion in insertions.iter_mut() {
+        let Some(ref_id) = header
+            .references
+            .iter()
+            .position(|r| r.name == insertion.chrom)
+        else {
+            insertion.genotype = None;
+            missing_contigs.insert(insertion.chrom.as_str());
+            continue;
+        };
+        // 0-based, half-open interval covering the site and any TSD
+        let (first, last) = match (insertion.left_breakpoint, insertion.right_breakpoint) {
+            (Some(l), Some(r)) => (l.min(r), l.max(r)),
+            _ => (insertion.position, insertion.position),
+        };
+        let start = first.saturating_sub(1 + params.min_flank);
+        let end = last + params.min_flank;
+
+        let ref_reads = reader
+            .query(&index, ref_id, start, end)?
+            .iter()
+            .filter(|r| {
+                !r.has_flag(
+                    flags::UNMAPPED | flags::SECONDARY | flags::SUPPLEMENTARY | flags::DUPLICATE,
+                )
+            })
+            .filter(|r| r.pos as u32 <= start && r.end() as u32 >= end)
+            .count() as u32;
+        insertion.genotype =
+            Genotype::from_counts(ref_reads, insertion.supporting_reads(), params.error_rate);
+    }
+    if !missing_contigs.is_empty() {
+        let contigs: Vec<&str> = missing_contigs.into_iter().collect();
+        println!(
+            "~~~~~ insertions on {} were left uncalled, as {} does not have these contigs",
+            contigs.join(", "),
+            bam.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_reads_leave_the_genotype_missing() {
+        assert_eq!(Genotype::from_counts(0, 0, 0.05), None);
+    }
+
+    #[test]
+    fn counts_give_the_most_likely_call() {
+        let call = |ref_reads, alt_reads| {
+            Genotype::from_counts(ref_reads, alt_reads, 0.05)
+                .unwrap()
+                .call
+        };
+        assert_eq!(call(20, 0), Call::HomRef);
+        assert_eq!(call(10, 10), Call::Het);
+        assert_eq!(call(0, 20), Call::HomAlt);
+    }
+
+    #[test]
+    fn pl_is_normalised_to_the_call() {
+        let genotype = Genotype::from_counts(10, 10, 0.05).unwrap();
+        assert_eq!(genotype.pl[1], 0);
+        assert!(genotype.pl[0] > 0 && genotype.pl[2] > 0);
+        assert_eq!(genotype.gq, genotype.pl[0].min(genotype.pl[2]).min(MAX_GQ));
+    }
+
+    #[test]
+    fn reference_reads_must_span_the_site() {
+        use crate::bai;
+        use crate::bam::{reg2bin, BamWriter, Header, Record, Reference};
+        use crate::breakpoint::Strand;
+        use crate::testdir::TempDir;
+
+        let read = |pos: i32, flag: u16| Record {
+            qname: format!("r{}", pos),
+            flag,
+            pos,
+            bin: reg2bin(pos, pos + 100),
+            cigar: "100M".parse().unwrap(),
+            seq: vec![b'A'; 100],
+            qual: vec![30; 100],
+            next_ref_id: -1,
+            next_pos: -1,
+            ..Record::default()
+        };
+        let mut records: Vec<Record> = (0..1000).map(|i| read(i * 10, 0)).collect();
+        // neither is counted
+        records.insert(496, read(4950, flags::DUPLICATE));
+        records.insert(496, read(4950, flags::SECONDARY));
+        let reference = |name: &str| Reference {
+            name: name.to_string(),
+            length: 100_000,
+        };
+        let header = Header {
+            text: "@HD\tVN:1.6\tSO:coordinate\n".to_string(),
+            references: vec![reference("chr1"), reference("chr2")],
+        };
+        let mut writer = BamWriter::new(Vec::new(), &header).unwrap();
+        for record in &records {
+            writer.write_record(record).unwrap();
+        }
+        let bam = writer.finish().unwrap();
+        let dir = TempDir::new("genotype");
+        std::fs::write(dir.join("s1.bam.bai"), bai::index_bytes(&bam)).unwrap();
+        std::fs::write(dir.join("s1.bam"), bam).unwrap();
+
+        let insertion = |chrom: &str, position: u32, breakpoints: Option<(u32, u32)>| Insertion {
+            chrom: chrom.to_string(),
+            position,
+            left_breakpoint: breakpoints.map(|(l, _)| l),
+            right_breakpoint: breakpoints.map(|(_, r)| r),
+            te_family: "HERVK".to_string(),
+            strand: Strand::Forward,
+            tsd_len: None,
+            chimeric_reads: if chrom == "chr1" { 6 } else { 0 },
+            split_reads: 0,
+            genotype: None,
+        };
+        let mut insertions = [
+            insertion("chr1", 5000, None),
+            // the flanks are taken outside the TSD
+            insertion("chr1", 7005, Some((7005, 7000))),
+            insertion("chr2", 5000, None),
+            insertion("chrUn", 5000, None),
+        ];
+        let params = GenotypeParams {
+            min_flank: 20,
+            error_rate: 0.05,
+        };
+        genotype_insertions(
+            &dir.join("s1.bam"),
+            &dir.join("s1.bam.bai"),
+            &mut insertions,
+            &params,
+        )
+        .unwrap();
+        let ref_reads: Vec<Option<u32>> = insertions
+            .iter()
+            .map(|i| i.genotype.as_ref().map(|g| g.ref_reads))
+            .collect();
+        // reads starting in [4920, 4979] and [6925, 6979]
+        assert_eq!(ref_reads, [Some(6), Some(5), None, None]);
+        assert_eq!(insertions[0].genotype.as_ref().unwrap().call, Call::Het);
+    }
+}
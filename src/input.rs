@@ -0,0 +1,544 @@
+//! Locating a sample's input files: explicit `--bam` or `--fq1`/`--fq2` paths, a
+//! sample sheet, or the `<Input_directory>/<sample>[_1|_2].<suffix>` naming, with
+//! each file's format checked from its first bytes.
+//!
+//! A sample sheet is a tab-separated file with one `sample_id<TAB>path[<TAB>path]`
+//! line per sample, where two paths are the mates of paired-end FASTQ. Relative
+//! paths are taken from the sheet's directory; `#` starts a comment line.
+//!
+//! Explicit and sample-sheet paths may be glob patterns such as `reads/S1_R1*.fq.gz`,
+//! each of which has to match exactly one file.
+
+use crate::aligner::Reads;
+use crate::alignment::AlignmentFormat;
+use crate::config::{PipelineConfig, SequencingType};
+use crate::merge;
+use anyhow::{anyhow, Context, Result};
+use flate2::read::MultiGzDecoder;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Alignment(AlignmentFormat),
+    /// Plain or gzip compressed FASTQ.
+    Fastq,
+}
+
+impl InputFormat {
+    /// Detects the format from the first bytes of the file, looking inside gzip
+    /// compression to tell BAM from compressed FASTQ.
+    pub fn detect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let magic = read_prefix(File::open(path)?)?;
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            let inner = read_prefix(MultiGzDecoder::new(File::open(path)?))?;
+            return if inner.starts_with(b"BAM\x01") {
+                Ok(InputFormat::Alignment(AlignmentFormat::Bam))
+            } else if inner.starts_with(b"@") {
+                Ok(InputFormat::Fastq)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "gzip data is neither BAM nor FASTQ",
+                ))
+            };
+        }
+        Ok(if magic.starts_with(b"CRAM") {
+            InputFormat::Alignment(AlignmentFormat::Cram)
+        } else if is_sam_header(&magic) || !magic.starts_with(b"@") {
+            InputFormat::Alignment(AlignmentFormat::Sam)
+        } else {
+            InputFormat::Fastq
+        })
+    }
+}
+
+/// Up to the first 16 bytes of `reader`.
+fn read_prefix<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(16);
+    reader.take(16).read_to_end(&mut prefix)?;
+    Ok(prefix)
+}
+
+/// Whether `bytes` start with a SAM header line such as `@HD<TAB>`, which a
+/// FASTQ header cannot be told apart from by the `@` alone.
+fn is_sam_header(bytes: &[u8]) -> bool {
+    matches!(bytes, [b'@', a, b, b'\t', ..] if a.is_ascii_uppercase() && b.is_ascii_uppercase())
+}
+
+/// A sample's input, located and checked.
+#[derive(Debug, Clone)]
+pub enum SampleInput {
+    Alignment {
+        path: PathBuf,
+        format: AlignmentFormat,
+    },
+    /// The alignment files listed in the `-m` file, to be read as one stream.
+    BamList(Vec<PathBuf>),
+    Fastq(Reads),
+}
+
+/// `<prefix><tail>.<suffix>`, where `suffix` may start with a dot.
+fn with_suffix(prefix: &Path, tail: &str, suffix: &str) -> PathBuf {
+    let mut name = prefix.as_os_str().to_owned();
+    name.push(format!("{}.{}", tail, suffix.trim_start_matches('.')));
+    PathBuf::from(name)
+}
+
+/// The FASTQ files `<prefix>_1.<suffix>` and `<prefix>_2.<suffix>` of paired-end
+/// reads, or `<prefix>.<suffix>` otherwise.
+pub fn fastq_reads(sequencing_type: SequencingType, prefix: &Path, suffix: &str) -> Reads {
+    match sequencing_type {
+        SequencingType::PairedEnd => Reads::Paired(
+            with_suffix(prefix, "_1", suffix),
+            with_suffix(prefix, "_2", suffix),
+        ),
+        SequencingType::SingleEnd => Reads::Single(with_suffix(prefix, "", suffix)),
+        SequencingType::Interleaved => Reads::Interleaved(with_suffix(prefix, "", suffix)),
+    }
+}
+
+/// Locates the input of `config.sample_id`, printing each file found. Fails with
+/// the full list of expected files when any is missing, or when a file's content
+/// does not match how it is used.
+pub fn discover(config: &PipelineConfig) -> Result<SampleInput> {
+    let (paths, source) = candidates(config)?;
+    check_found(&paths, &config.sample_id, source)?;
+
+    if config.multiple_bam {
+        let [list] = &paths[..] else {
+            return Err(anyhow!("-m expects a single file listing the BAM files"));
+        };
+        let bams = merge::read_bam_list(list)?;
+        check_found(&bams, &config.sample_id, "-m list")?;
+        for (path, format) in bams.iter().zip(detect_all(&bams)?) {
+            if format == InputFormat::Fastq {
+                return Err(anyhow!(
+                    "{} is FASTQ, -m lists alignment files",
+                    path.display()
+                ));
+            }
+        }
+        return Ok(SampleInput::BamList(bams));
+    }
+    let formats = detect_all(&paths)?;
+    match (&paths[..], &formats[..]) {
+        ([path], [InputFormat::Alignment(format)]) => Ok(SampleInput::Alignment {
+            path: path.clone(),
+            format: *format,
+        }),
+        ([fq], [InputFormat::Fastq]) => match config.sequencing_type {
+            SequencingType::SingleEnd => Ok(SampleInput::Fastq(Reads::Single(fq.clone()))),
+            SequencingType::Interleaved => Ok(SampleInput::Fastq(Reads::Interleaved(fq.clone()))),
+            SequencingType::PairedEnd => Err(anyhow!(
+                "{} is a single FASTQ file; give the second mate with --fq2 or use -s interleaved",
+                fq.display()
+            )),
+        },
+        ([fq1, fq2], [InputFormat::Fastq, InputFormat::Fastq]) => {
+            if config.sequencing_type != SequencingType::PairedEnd {
+                return Err(anyhow!(
+                    "two FASTQ files were given for {} data",
+                    config.sequencing_type
+                ));
+            }
+            Ok(SampleInput::Fastq(Reads::Paired(fq1.clone(), fq2.clone())))
+        }
+        _ => {
+            let described: Vec<String> = paths
+                .iter()
+                .zip(&formats)
+                .map(|(path, format)| format!("{} ({:?})", path.display(), format))
+                .collect();
+            Err(anyhow!(
+                "unexpected combination of input files: {}",
+                described.join(", ")
+            ))
+        }
+    }
+}
+
+/// Prints each of `paths` that exists, failing with the list of those that do not.
+fn check_found(paths: &[PathBuf], sample_id: &str, source: &str) -> Result<()> {
+    let missing: Vec<&PathBuf> = paths.iter().filter(|path| !path.is_file()).collect();
+    for path in paths {
+        if !missing.contains(&path) {
+            println!("~~~~~ found {}", path.display());
+        }
+    }
+    if !missing.is_empty() {
+        let list: Vec<String> = missing.iter().map(|p| p.display().to_string()).collect();
+        return Err(anyhow!(
+            "missing input for sample {} ({}): {}",
+            sample_id,
+            source,
+            list.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn detect_all(paths: &[PathBuf]) -> Result<Vec<InputFormat>> {
+    paths
+        .iter()
+        .map(|path| {
+            InputFormat::detect(path).with_context(|| format!("could not read {}", path.display()))
+        })
+        .collect()
+}
+
+/// The files the input should consist of, and where that expectation came from.
+fn candidates(config: &PipelineConfig) -> Result<(Vec<PathBuf>, &'static str)> {
+    if let Some(bam) = &config.bam {
+        return Ok((vec![expand(bam)?], "--bam"));
+    }
+    if let Some(fq1) = &config.fq1 {
+        let mut paths = vec![expand(fq1)?];
+        if let Some(fq2) = &config.fq2 {
+            paths.push(expand(fq2)?);
+        }
+        return Ok((paths, "--fq1/--fq2"));
+    }
+    if let Some(sheet) = &config.sample_sheet {
+        let paths = sample_sheet_paths(sheet, &config.sample_id)?
+            .iter()
+            .map(|path| expand(path))
+            .collect::<Result<_>>()?;
+        return Ok((paths, "sample sheet"));
+    }
+    let prefix = config.input_directory.join(&config.sample_id);
+    let suffix = config.file_suffix.to_ascii_lowercase();
+    let paths = if suffix.contains("fq") || suffix.contains("fastq") {
+        fastq_reads(config.sequencing_type, &prefix, &config.file_suffix)
+            .files()
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect()
+    } else {
+        vec![with_suffix(&prefix, "", &config.file_suffix)]
+    };
+    Ok((paths, "-I/-i/-f"))
+}
+
+/// The single file matching `path` if it is a glob pattern, or `path` itself.
+/// A pattern without matches is returned unchanged to be reported as missing.
+fn expand(path: &Path) -> Result<PathBuf> {
+    let pattern = path.to_string_lossy();
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(path.to_path_buf());
+    }
+    let matches = glob::glob(&pattern)
+        .with_context(|| format!("invalid pattern {}", pattern))?
+        .collect::<Result<Vec<_>, _>>()?;
+    match &matches[..] {
+        [] => Ok(path.to_path_buf()),
+        [file] => Ok(file.clone()),
+        _ => {
+            let list: Vec<String> = matches.iter().map(|p| p.display().to_string()).collect();
+            Err(anyhow!(
+                "{} matches more than one file: {}",
+                pattern,
+                list.join(", ")
+            ))
+        }
+    }
+}
+
+/// The samples of the sample sheet at `sheet`, in order.
+pub fn sample_sheet_ids(sheet: &Path) -> Result<Vec<String>> {
+    Ok(read_sample_sheet(sheet)?
+        .into_iter()
+        .map(|(sample_id, _)| sample_id)
+        .collect())
+}
+
+/// The paths listed for `sample_id` in the sample sheet at `sheet`.
+fn sample_sheet_paths(sheet: &Path, sample_id: &str) -> Result<Vec<PathBuf>> {
+    read_sample_sheet(sheet)?
+        .into_iter()
+        .find(|(id, _)| id == sample_id)
+        .map(|(_, paths)| paths)
+        .ok_or_else(|| anyhow!("sample {} is not listed in {}", sample_id, sheet.display()))
+}
+
+/// The samples of a sample sheet with their paths.
+fn read_sample_sheet(sheet: &Path) -> Result<Vec<(String, Vec<PathBuf>)>> {
+    let text = fs::read_to_string(sheet)
+        .with_context(|| format!("could not read sample sheet {}", sheet.display()))?;
+    let base = sheet.parent().unwrap_or(Path::new(""));
+    let mut samples: Vec<(String, Vec<PathBuf>)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(anyhow!(
+                "{}:{}: expected a sample id and one or two paths",
+                sheet.display(),
+                n + 1
+            ));
+        }
+        if samples.iter().any(|(id, _)| id == fields[0]) {
+            return Err(anyhow!(
+                "{}:{}: sample {} is listed twice",
+                sheet.display(),
+                n + 1,
+                fields[0]
+            ));
+        }
+        let paths = fields[1..].iter().map(|path| base.join(path)).collect();
+        samples.push((fields[0].to_string(), paths));
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::testdir::TempDir;
+
+    const FASTQ: &str = "@r1\nACGT\n+\nIIII\n";
+
+    /// The configuration of sample `s1` with `dir` as the input directory.
+    fn config(dir: &TempDir, settings: Settings) -> PipelineConfig {
+        Settings {
+            sample_id: Some("s1".to_string()),
+            input_directory: Some(dir.path().to_path_buf()),
+            human_reference: Some("hg38.fa".to_string()),
+            te_reference: Some("te.fa".to_string()),
+            ..settings
+        }
+        .resolve()
+        .unwrap()
+    }
+
+    fn error(config: &PipelineConfig) -> String {
+        discover(config).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn fastq_reads_follow_the_sequencing_type() {
+        let prefix = Path::new("in/s1");
+        let files = |reads: Reads| -> Vec<PathBuf> {
+            reads.files().into_iter().map(Path::to_path_buf).collect()
+        };
+        assert_eq!(
+            files(fastq_reads(SequencingType::PairedEnd, prefix, ".fq.gz")),
+            [
+                PathBuf::from("in/s1_1.fq.gz"),
+                PathBuf::from("in/s1_2.fq.gz")
+            ]
+        );
+        assert_eq!(
+            files(fastq_reads(SequencingType::SingleEnd, prefix, "fastq")),
+            [PathBuf::from("in/s1.fastq")]
+        );
+        assert!(matches!(
+            fastq_reads(SequencingType::Interleaved, prefix, "fq"),
+            Reads::Interleaved(fq) if fq == Path::new("in/s1.fq")
+        ));
+    }
+
+    #[test]
+    fn paired_fastq_from_the_input_directory() {
+        let dir = TempDir::with_files("paired", &[("s1_1.fq", FASTQ), ("s1_2.fq", FASTQ)]);
+        let config = config(
+            &dir,
+            Settings {
+                file_suffix: Some("fq".to_string()),
+                ..Settings::default()
+            },
+        );
+        assert!(matches!(
+            discover(&config).unwrap(),
+            SampleInput::Fastq(Reads::Paired(..))
+        ));
+    }
+
+    #[test]
+    fn missing_mate_is_listed() {
+        let dir = TempDir::with_files("missing", &[("s1_1.fq", FASTQ)]);
+        let config = config(
+            &dir,
+            Settings {
+                file_suffix: Some("fq".to_string()),
+                ..Settings::default()
+            },
+        );
+        let message = error(&config);
+        assert!(message.starts_with("missing input for sample s1 (-I/-i/-f): "));
+        assert!(message.ends_with("s1_2.fq"));
+    }
+
+    #[test]
+    fn single_fastq_needs_a_single_end_mode() {
+        let dir = TempDir::with_files("single", &[("s1.fq", FASTQ)]);
+        let fq1 = Some(dir.join("s1.fq"));
+        let paired = config(
+            &dir,
+            Settings {
+                fq1: fq1.clone(),
+                ..Settings::default()
+            },
+        );
+        assert!(error(&paired).contains("give the second mate with --fq2 or use -s interleaved"));
+        let interleaved = config(
+            &dir,
+            Settings {
+                fq1,
+                sequencing_type: Some(SequencingType::Interleaved),
+                ..Settings::default()
+            },
+        );
+        assert!(matches!(
+            discover(&interleaved).unwrap(),
+            SampleInput::Fastq(Reads::Interleaved(_))
+        ));
+    }
+
+    #[test]
+    fn two_fastq_files_need_paired_end_mode() {
+        let dir = TempDir::with_files("two", &[("s1_1.fq", FASTQ), ("s1_2.fq", FASTQ)]);
+        let config = config(
+            &dir,
+            Settings {
+                fq1: Some(dir.join("s1_1.fq")),
+                fq2: Some(dir.join("s1_2.fq")),
+                sequencing_type: Some(SequencingType::SingleEnd),
+                ..Settings::default()
+            },
+        );
+        assert_eq!(
+            error(&config),
+            "two FASTQ files were given for single-end data"
+        );
+    }
+
+    #[test]
+    fn bam_list_must_not_list_fastq() {
+        let dir = TempDir::with_files(
+            "list",
+            &[
+                ("s1.list", "# lanes\ns1.sam\n\ns1.fq\n"),
+                ("s1.sam", "@HD\tVN:1.6\n"),
+                ("s1.fq", FASTQ),
+            ],
+        );
+        let config = config(
+            &dir,
+            Settings {
+                file_suffix: Some("list".to_string()),
+                multiple_bam: Some(true),
+                ..Settings::default()
+            },
+        );
+        assert_eq!(
+            error(&config),
+            format!(
+                "{} is FASTQ, -m lists alignment files",
+                dir.join("s1.fq").display()
+            )
+        );
+    }
+
+    #[test]
+    fn sample_sheet_rows() {
+        let dir = TempDir::with_files(
+            "sheet",
+            &[(
+                "samples.tsv",
+                "# sample\tpaths\ns2\treads/s2_1.fq\treads/s2_2.fq\n\ns1\t/data/s1.bam\n",
+            )],
+        );
+        let sheet = dir.join("samples.tsv");
+        assert_eq!(sample_sheet_ids(&sheet).unwrap(), ["s2", "s1"]);
+        assert_eq!(
+            sample_sheet_paths(&sheet, "s2").unwrap(),
+            [dir.join("reads/s2_1.fq"), dir.join("reads/s2_2.fq")]
+        );
+        assert_eq!(
+            sample_sheet_paths(&sheet, "s1").unwrap(),
+            [PathBuf::from("/data/s1.bam")]
+        );
+        assert_eq!(
+            sample_sheet_paths(&sheet, "s3").unwrap_err().to_string(),
+            format!("sample s3 is not listed in {}", sheet.display())
+        );
+    }
+
+    #[test]
+    fn sample_sheet_errors() {
+        let dir = TempDir::with_files(
+            "sheet",
+            &[
+                ("twice.tsv", "s1\ta.bam\n# comment\ns1\tb.bam\n"),
+                ("no_path.tsv", "s1\n"),
+                ("three_paths.tsv", "s1\ta.fq\tb.fq\tc.fq\n"),
+            ],
+        );
+        let error = |file: &str| {
+            let sheet = dir.join(file);
+            let message = sample_sheet_ids(&sheet).unwrap_err().to_string();
+            message.replace(&sheet.display().to_string(), file)
+        };
+        assert_eq!(error("twice.tsv"), "twice.tsv:3: sample s1 is listed twice");
+        assert_eq!(
+            error("no_path.tsv"),
+            "no_path.tsv:1: expected a sample id and one or two paths"
+        );
+        assert_eq!(
+            error("three_paths.tsv"),
+            "three_paths.tsv:1: expected a sample id and one or two paths"
+        );
+    }
+
+    #[test]
+    fn patterns_match_exactly_one_file() {
+        let dir = TempDir::with_files(
+            "glob",
+            &[("S1_R1_001.fq.gz", FASTQ), ("S1_R2_001.fq.gz", FASTQ)],
+        );
+        let expand = |pattern: &str| expand(&dir.join(pattern));
+
+        assert_eq!(expand("S1_R1*.fq.gz").unwrap(), dir.join("S1_R1_001.fq.gz"));
+        // without a match the pattern is kept, to be reported as missing
+        assert_eq!(expand("S2_R1*.fq.gz").unwrap(), dir.join("S2_R1*.fq.gz"));
+        let err = expand("S1_R?_001.fq.gz").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} matches more than one file: {}, {}",
+                dir.join("S1_R?_001.fq.gz").display(),
+                dir.join("S1_R1_001.fq.gz").display(),
+                dir.join("S1_R2_001.fq.gz").display()
+            )
+        );
+        assert_eq!(
+            expand("S1_R1_001.fq.gz").unwrap(),
+            dir.join("S1_R1_001.fq.gz")
+        );
+    }
+
+    #[test]
+    fn formats_are_detected_from_content() {
+        let dir = TempDir::with_files(
+            "formats",
+            &[
+                ("a.sam", "@HD\tVN:1.6\n"),
+                ("b.bam", FASTQ),
+                ("c", "r1\t4\t*\t0\t0\t*\n"),
+            ],
+        );
+        let detect = |file: &str| InputFormat::detect(dir.join(file)).unwrap();
+        assert_eq!(
+            detect("a.sam"),
+            InputFormat::Alignment(AlignmentFormat::Sam)
+        );
+        assert_eq!(detect("b.bam"), InputFormat::Fastq);
+        assert_eq!(detect("c"), InputFormat::Alignment(AlignmentFormat::Sam));
+    }
+}
@@ -1,120 +1,213 @@
-use anyhow::{anyhow, Error, Result};
-use clap::{command, error::ErrorKind, Parser};
-use regex::Regex;
-use std::f32::consts::E;
+mod aligner;
+mod alignment;
+mod anchor;
+mod bai;
+mod bam;
+mod bgzf;
+mod breakpoint;
+mod bwa;
+mod calling;
+mod chimeric;
+mod cmd;
+mod config;
+mod fasta;
+mod fastq;
+mod filter;
+mod genotype;
+mod input;
+mod merge;
+mod population;
+mod sam;
+mod softclip;
+mod tags;
+#[cfg(test)]
+mod testdir;
+mod vcf;
+
+use aligner::{AlignerKind, Reads};
+use alignment::{AlignmentFormat, AlignmentReader, SamPipe};
+use anchor::Anchor;
+use anyhow::{anyhow, Context, Result};
+use bam::{flags, BamWriter, Header, Record};
+use breakpoint::Breakpoint;
+use calling::Insertion;
+use clap::{Parser, Subcommand};
+use cmd::Cmd;
+use config::{DataType, PipelineConfig, SequencingType, Settings};
+use fasta::IndexedFasta;
+use fastq::{FastqRecord, FastqWriter};
+use genotype::{Call, Genotype, GenotypeParams};
+use input::SampleInput;
+use merge::MergedReader;
+use population::SiteStats;
+use softclip::{ClipSide, SoftClip};
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, BufWriter, LineWriter, Write};
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
 use std::{collections::HashMap, env};
+use vcf::VcfWriter;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct GetOptions {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML or YAML file with default settings, its relative paths taken from its
+    /// directory; options given here take precedence
+    #[arg(long = "config")]
+    config: Option<PathBuf>,
     #[arg(short = 'i', long = "input_sampleID")]
-    input_sample_id: String,
+    input_sample_id: Option<String>,
+    /// [default: bam]
     #[arg(short = 'f', long = "file_suffix")]
-    file_suffix: String,
+    file_suffix: Option<String>,
     #[arg(short = 'H', long = "Human_reference_genome")]
-    human_reference_genome: String,
+    human_reference_genome: Option<String>,
     #[arg(short = 'T', long = "TE_reference_genomes")]
-    te_reference_genome: String,
+    te_reference_genome: Option<String>,
+    /// [default: current directory]
     #[arg(short = 'I', long = "Input_directory")]
-    input_directory: String,
+    input_directory: Option<PathBuf>,
+    /// [default: current directory]
     #[arg(short = 'O', long = "Output_directory")]
-    output_directory: String,
+    output_directory: Option<PathBuf>,
+    /// Alignment input (BAM, CRAM or SAM) instead of -I/-i/-f naming; may be a glob
+    #[arg(long = "bam")]
+    bam: Option<PathBuf>,
+    /// FASTQ input, the first mate of paired-end reads; may be a glob
+    #[arg(long = "fq1")]
+    fq1: Option<PathBuf>,
+    /// FASTQ input of the second mate
+    #[arg(long = "fq2", requires = "fq1")]
+    fq2: Option<PathBuf>,
+    /// Tab-separated file of sample IDs and their input files
+    #[arg(long = "sample_sheet")]
+    sample_sheet: Option<PathBuf>,
+    /// Minimum number of supporting reads [default: 3]
     #[arg(short = 'n', long = "number_of_reads")]
     number_of_reads: Option<u32>,
-    #[arg(short = 'd', long = "data_type")]
-    data_type: String,
-    #[arg(short = 's', long = "sequencing_type")]
-    sequencing_type: String,
+    /// [default: WGS]
+    #[arg(short = 'd', long = "data_type", value_enum, ignore_case = true)]
+    data_type: Option<DataType>,
+    /// [default: paired-end]
+    #[arg(short = 's', long = "sequencing_type", value_enum, ignore_case = true)]
+    sequencing_type: Option<SequencingType>,
+    /// [default: 500]
     #[arg(short = 'l', long = "length_insertsize")]
     length_insert_size: Option<f32>,
     #[arg(short = 'L', long = "L_std_insertsize")]
     l_std_insert_size: Option<f32>,
+    /// [default: 100]
     #[arg(short = 'r', long = "read_len")]
     read_len: Option<u32>,
+    /// [default: 1]
     #[arg(short = 't', long = "threads")]
     threads: Option<u32>,
+    /// Minimum length of a soft clip used as a split read [default: 20]
     #[arg(short = 'S', long = "Split")]
     split: Option<u32>,
+    /// Only use soft clips on this side of the alignment [default: both]
+    #[arg(long = "clip_side", value_enum)]
+    clip_side: Option<ClipSide>,
+    /// Minimum mean base quality of a soft-clipped segment [default: 0]
+    #[arg(long = "min_clip_quality")]
+    min_clip_quality: Option<f64>,
+    /// Largest fraction of a soft-clipped segment one base may make up [default: 0.9]
+    #[arg(long = "max_clip_base_fraction")]
+    max_clip_base_fraction: Option<f64>,
     #[arg(short = 'm', long = "multiple_BAM")]
     multiple_bam: bool,
     #[arg(short = 'B', long = "BWA_MEM")]
     bwa_mem: bool,
+    /// Aligner for the human reference [default: bowtie2, or bwa with -B]
+    #[arg(long = "human_aligner", value_enum)]
+    human_aligner: Option<AlignerKind>,
+    /// Aligner for the TE library [default: bwa]
+    #[arg(long = "te_aligner", value_enum)]
+    te_aligner: Option<AlignerKind>,
     #[arg(short = 'G', long = "Genotype")]
     genotype: bool,
+    /// Minimum AS of a uniquely mapped anchor read [default: 30]
+    #[arg(long = "alignment_score")]
+    alignment_score: Option<i32>,
+    /// Minimum AS/XS ratio of an anchor read with a suboptimal hit [default: 2]
+    #[arg(long = "min-as-xs-ratio")]
+    min_as_xs_ratio: Option<f64>,
 }
-impl GetOptions {
-    fn normalize(&mut self) {
-        if self.file_suffix.is_empty() {
-            self.file_suffix = ".bam".to_string();
-        }
-        if !self.read_len.is_some() {
-            self.read_len = Some(100);
-        }
-        if !self.split.is_some() {
-            self.split = Some(20);
-        }
-        if !self.threads.is_some() {
-            self.threads = Some(1);
-        }
 
-        if self.input_directory.is_empty() {
-            //perl add /
-            if let Ok(current_dir) = env::current_dir() {
-                self.input_directory = current_dir.to_string_lossy().into_owned();
-            } else {
-                eprintln!("Failed to get current directory.");
-            }
-        }
-        if self.output_directory.is_empty() {
-            if let Ok(current_dir) = env::current_dir() {
-                self.output_directory = current_dir.to_string_lossy().into_owned();
-            } else {
-                eprintln!("Failed to get current directory.");
-            }
-        }
-        if !self.number_of_reads.is_some() {
-            self.number_of_reads = Some(3);
-        }
-        if self.data_type.is_empty() {
-            self.data_type = "WGS".to_string();
-        }
-        if self.sequencing_type.is_empty() {
-            self.sequencing_type = String::from("paired-end");
-        }
-        if self.sequencing_type.eq_ignore_ascii_case("single-end") {
-            self.length_insert_size = Some(500f32);
-        }
-        // if self.length_insert_size.is_some() && self.l_std_insert_size.is_some(){
-        //   self.std
-        // }
+#[derive(Subcommand)]
+enum Command {
+    /// Call insertions jointly across the samples of --sample_sheet into one
+    /// multi-sample VCF; -i names the cohort
+    Cohort,
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the effective configuration as TOML, usable as a --config file
+    Dump,
+}
 
-        // GetOptions { input_sample_id: self.input_sample_id, file_suffix: self.file_suffix, human_reference_genome: self.human_reference_genome, te_reference_genome: self.te_reference_genome, input_directory: self.input_directory, output_directory: self.output_directory, number_of_reads: self.number_of_reads, data_type: self.data_type, sequencing_type: self.sequencing_type, length_insert_size: self.length_insert_size, l_std_insert_size: self.l_std_insert_size, read_len: self.read_len, threads: self.threads, split: self.split, multiple_bam: self.multiple_bam, bwa_mem: self.bwa_mem, genotype: self.genotype }
+impl GetOptions {
+    /// Merges the options with the `--config` file, if any, and resolves them.
+    fn into_config(self) -> Result<PipelineConfig> {
+        let file = match &self.config {
+            Some(path) => Settings::load(path)?,
+            None => Settings::default(),
+        };
+        // switches can only turn a setting on
+        let flag = |set: bool| set.then_some(true);
+        Settings {
+            sample_id: self.input_sample_id,
+            file_suffix: self.file_suffix,
+            input_directory: self.input_directory,
+            output_directory: self.output_directory,
+            bam: self.bam,
+            fq1: self.fq1,
+            fq2: self.fq2,
+            sample_sheet: self.sample_sheet,
+            human_reference: self.human_reference_genome,
+            te_reference: self.te_reference_genome,
+            data_type: self.data_type,
+            sequencing_type: self.sequencing_type,
+            insert_size: self.length_insert_size,
+            insert_size_sd: self.l_std_insert_size,
+            read_len: self.read_len,
+            threads: self.threads,
+            min_reads: self.number_of_reads,
+            multiple_bam: flag(self.multiple_bam),
+            bwa_aligned_input: flag(self.bwa_mem),
+            human_aligner: self.human_aligner,
+            te_aligner: self.te_aligner,
+            min_alignment_score: self.alignment_score,
+            min_as_xs_ratio: self.min_as_xs_ratio,
+            min_clip_len: self.split,
+            clip_side: self.clip_side,
+            min_clip_quality: self.min_clip_quality,
+            max_clip_base_fraction: self.max_clip_base_fraction,
+            genotype: flag(self.genotype),
+            ..Settings::default()
+        }
+        .or(file)
+        .resolve()
     }
 }
+
 fn main() -> Result<()> {
-    let mut std_insert_size: f32 = 0.0;
-    // my $bowtie2_d="";
-    // my $tophat_d="";
-    // my $bwa_d="";
-    // my $samtools_d="";
-    // my $SE_MEI_d="";
-    const DEFAULT_THREADS: u32 = 1;
-
-    let bowtie2_d = "";
-    let tophat_d = "";
-    let bwa_d = "";
-    let samtools_d = "";
-    let se_mei_d = "";
-
-    let mut directory = String::new();
-    let mut args = GetOptions::parse();
-    args.normalize();
-    if args.length_insert_size.is_some() && args.l_std_insert_size.is_some() {
-        std_insert_size = args.l_std_insert_size.unwrap();
+    let mut options = GetOptions::parse();
+    let command = options.command.take();
+    let config = options.into_config()?;
+    if let Some(Command::Config {
+        action: ConfigAction::Dump,
+    }) = command
+    {
+        config.write_toml(&mut io::stdout().lock())?;
+        return Ok(());
     }
 
     println!();
@@ -140,59 +233,42 @@ fn main() -> Result<()> {
 
     println!("\nStep 1: Loading...\n=====================================");
 
-    if args.input_sample_id.is_empty() {
-        eprintln!(
-            "{} Error \n# No samples are provided ",
-            ErrorKind::InvalidValue
-        );
-        std::process::exit(1);
-    }
-
-    let argss: Vec<String> = env::args().collect();
-
-    if let Some(program_name) = argss.get(0) {
-        directory.push_str(program_name);
-        println!("{}", directory);
-    }
-
-    let mut components: Vec<&str> = directory.split('/').collect();
-
-    if components.len() > 1 {
-        components.pop(); // Remove the last component
-        directory = components.join("/");
-        directory.push('/');
-    } else {
-        directory = String::new();
-    }
-
-    let mut tsd_min_len = 100;
-    let mut alignment_score = 30;
-    let mut human_genome = &args.human_reference_genome;
-    let mut human_genome_tophat = &args.human_reference_genome;
-
-    let mut genome: HashMap<String, String> = HashMap::new();
-    let mut order = 0;
-    let min_insertsize = 0;
-    let thread_1 = args.threads.unwrap() - 1;
-    let double_length_insertsize = 0.0f64; // Define the correct type
-    let cmd = String::new(); // You can use String for dynamic strings
-    let mut bp1_tmp3: Vec<String> = Vec::new(); // Define the correct type
-    let header3 = "Sample_ID Is_Split_mode ... Genotype\n".to_string();
-
     /////////////////////////////////
-    /// create output director passed from cli args if not exists
-    if let Err(err) = create_directory_if_not_exists(&args.output_directory) {
+    // create output director passed from cli args if not exists
+    if let Err(err) = create_directory_if_not_exists(&config.output_directory) {
         eprintln!("Failed to create directory: {}", err);
     }
 
     //line 213 on Perl
     //set working directory...
-    if let Err(err) = env::set_current_dir(&args.output_directory) {
+    if let Err(err) = env::set_current_dir(&config.output_directory) {
         eprintln!("Failed to change directory: {}", err);
     }
 
+    let settings_file = format!("{}.settings.toml", &config.sample_id);
+    let mut settings = BufWriter::new(File::create(&settings_file)?);
+    config.write_metadata(&mut settings)?;
+    settings.flush()?;
+    println!("~~~~~ run settings were written to {}", settings_file);
+
+    match command {
+        Some(Command::Cohort) => run_cohort(&config),
+        _ => run_sample(&config),
+    }
+}
+
+/// The breakpoint evidence of one sample, and the indexed BAM its insertions can
+/// be genotyped against unless the input was a list of BAM files.
+struct SampleEvidence {
+    breakpoints: Vec<Breakpoint>,
+    genotype_bam: Option<PathBuf>,
+}
+
+/// Steps 2.1 to 2.4 for `config.sample_id`: locates the input, extracts the
+/// supporting reads, aligns them to the TE library and writes `<id>_all_breakpoint`.
+fn extract_evidence(config: &PipelineConfig) -> Result<SampleEvidence> {
     //////// 2.1 Check input file
-    let temp_directory = format!("{}_temp", &args.input_sample_id);
+    let temp_directory = format!("{}_temp", &config.sample_id);
 
     if !Path::new(&temp_directory).exists() {
         if let Err(err) = fs::create_dir(&temp_directory) {
@@ -200,466 +276,781 @@ fn main() -> Result<()> {
         }
     }
     println!("\nStep 2: Detecting TE insertions...\n=====================================\n");
-    let input_file_1 = format!(
-        "{}{}_1.{}",
-        &args.input_directory, &args.input_sample_id, &args.file_suffix
-    );
-    let input_file_2 = format!(
-        "{}{}_2.{}",
-        &args.input_directory, &args.input_sample_id, &args.file_suffix
-    );
-    let sequencing_type = "paired-end"; // Replace with the sequencing type
-
-    let file_suffix_pattern = Regex::new(r"fq|fastq").unwrap(); // Regex pattern for "fq" or "fastq"
-    let bam_suffix_pattern = Regex::new(r"bam|sam").unwrap(); // Regex pattern for "fq" or "fastq"
-
-    let input_file = format!(
-        "{}{}.{}",
-        &args.input_directory, &args.input_sample_id, &args.file_suffix
-    );
-
-    if Path::new(&input_file_1).exists()
-        && Path::new(&input_file_2).exists()
-        && sequencing_type == "paired-end"
-        && file_suffix_pattern.is_match(&args.file_suffix)
-    {
-        println!("~~~~~ paired-end reads in fastq format were loaded");
-    } else if Path::new(&input_file).exists()
-        && sequencing_type == "single-end"
-        && file_suffix_pattern.is_match(&args.file_suffix)
-    {
-        println!("~~~~~ single-end read in fastq format was loaded");
-    } else if Path::new(&input_file).exists()
-        && sequencing_type == "paired-end"
-        && bam_suffix_pattern.is_match(&args.file_suffix)
-    {
-        println!("~~~~~ paired-end reads in bam format were loaded\n");
-        if args.genotype {
-            let bai_file = format!(
-                "{}{}.{}.bai",
-                &args.input_directory, &args.input_sample_id, &args.file_suffix
+    let input = input::discover(config)?;
+    match &input {
+        SampleInput::Alignment { path, format } => {
+            println!(
+                "~~~~~ {} reads in {:?} format were loaded",
+                config.sequencing_type, format
             );
-
-            if Path::new(&bai_file).exists() {
-                println!("~~~~~ the input bam file was indexed");
-            } else {
-                println!("~~~~~ the input bam file was not indexed, please index the bam file using samtools for performing the validation or genotyping function");
-                std::process::exit(1);
-            }
-        }
-    } else if Path::new(&input_file).exists()
-        && sequencing_type == "single-end"
-        && bam_suffix_pattern.is_match(&args.file_suffix)
-    {
-        println!("~~~~~ single-end reads in bam format were loaded");
-        if args.genotype {
-            let bai_file = format!(
-                "{}{}.{}.bai",
-                &args.input_directory, &args.input_sample_id, &args.file_suffix
-            );
-
-            if Path::new(&bai_file).exists() {
+            if config.genotype.is_some() {
+                if *format != AlignmentFormat::Bam {
+                    return Err(anyhow!(
+                        "genotyping needs BAM input, {} is {:?}",
+                        path.display(),
+                        format
+                    ));
+                }
+                if !bai_path(path).exists() {
+                    return Err(anyhow!(
+                        "genotyping needs an indexed BAM, {} has no .bai; index it with samtools index",
+                        path.display()
+                    ));
+                }
                 println!("~~~~~ the input bam file was indexed");
-            } else {
-                println!("~~~~~ the input bam file was not indexed, please index the bam file using samtools for performing the validation or genotyping function");
-                std::process::exit(1);
             }
         }
-    } else if Path::new(&input_file).exists() && args.multiple_bam {
-        println!("~~~~~ a list of multiple BAM files were loaded");
-    } else {
-        eprintln!("# Error: could not find the input data under the provided sampleID");
-        println!(
-            "Input: {}{}{}",
-            &args.input_directory, &args.input_sample_id, &args.file_suffix
-        );
-        std::process::exit(1);
+        SampleInput::BamList(paths) => println!(
+            "~~~~~ a list of {} alignment files was loaded and is read as one",
+            paths.len()
+        ),
+        SampleInput::Fastq(_) => println!(
+            "~~~~~ {} reads in fastq format were loaded",
+            config.sequencing_type
+        ),
     }
+    // reads extracted from alignments are written with one file per mate
+    let extracted = if config.paired() {
+        SequencingType::PairedEnd
+    } else {
+        SequencingType::SingleEnd
+    };
 
     ////// Step 2.2 Extract supporting reads
-    if args.file_suffix.eq_ignore_ascii_case("bam") || args.multiple_bam {
-        let order = 1;
-        convert_bamtofastq(&args.input_sample_id);
-        if !args.bwa_mem {
-            align_to_hg(&format!("{}_h1", &args.input_sample_id), ".1fq");
-            let order = 2;
-            convert_bamtofastq(&format!("{}_h1", &args.input_sample_id));
-            if std::path::Path::new(&format!("{}_h1_sm.bam", &args.input_sample_id)).exists() {
+    match &input {
+        SampleInput::Alignment { path, .. } => convert_bamtofastq(config, path, &config.sample_id)?,
+        SampleInput::BamList(paths) => {
+            let merged =
+                MergedReader::open(paths, &config.sample_id, Some(&config.human_reference))?;
+            let header = merged.header().clone();
+            extract_reads(config, &header, merged, &config.sample_id)?;
+        }
+        SampleInput::Fastq(_) => {}
+    }
+    if let SampleInput::Fastq(reads) = &input {
+        // the alignment is only indexed when it is genotyped against
+        align_to_hg(config, reads, &config.sample_id, config.genotype.is_some())?;
+        convert_bamtofastq(
+            config,
+            Path::new(&format!("{}.bam", &config.sample_id)),
+            &config.sample_id,
+        )?;
+        if config.split_reads {
+            gunzip(
+                &[format!("{}_soft.fastq.gz", &config.sample_id)],
+                &format!("{}_1sf.fastq", &config.sample_id),
+            )?;
+        }
+        if config.paired() {
+            move_files_fs(
+                &format!("{}_h1_1.1fq", &config.sample_id),
+                &format!("{}_1.1fq", &config.sample_id),
+            )?;
+            move_files_fs(
+                &format!("{}_h1_2.1fq", &config.sample_id),
+                &format!("{}_2.1fq", &config.sample_id),
+            )?;
+        }
+    } else {
+        if !config.bwa_aligned_input {
+            let name = format!("{}_h1", &config.sample_id);
+            align_to_hg(
+                config,
+                &input::fastq_reads(extracted, Path::new(&name), "1fq"),
+                &name,
+                false,
+            )?;
+            convert_bamtofastq(
+                config,
+                Path::new(&format!("{}_h1.bam", &config.sample_id)),
+                &format!("{}_h1", &config.sample_id),
+            )?;
+            if std::path::Path::new(&format!("{}_h1_sm.bam", &config.sample_id)).exists() {
                 std::fs::rename(
-                    &format!("{}_h1_sm.bam", &args.input_sample_id),
-                    &format!("{}_sm.bam", &args.input_sample_id),
+                    format!("{}_h1_sm.bam", &config.sample_id),
+                    format!("{}_sm.bam", &config.sample_id),
                 )
                 .expect("Failed to rename file");
             }
-            if std::path::Path::new(&format!("{}_h1_su.bam", &args.input_sample_id)).exists() {
+            if std::path::Path::new(&format!("{}_h1_su.bam", &config.sample_id)).exists() {
                 std::fs::rename(
-                    &format!("{}_h1_su.bam", &args.input_sample_id),
-                    &format!("{}_su.bam", &args.input_sample_id),
+                    format!("{}_h1_su.bam", &config.sample_id),
+                    format!("{}_su.bam", &config.sample_id),
                 )
                 .expect("Failed to rename file");
             }
         }
 
-        //
-        if args.split.is_some() || &args.sequencing_type == "single-end" {
-            gunzip(&format!(
-                "{}_soft.fastq.gz >{}_1sf.fastq",
-                &args.input_sample_id, &args.input_sample_id
-            ))?;
-            // Capture the output of the first gunzip and write it to output_1sf
-
-            if !args.bwa_mem {
-                gunzip(&format!(
-                    "{}_h1_soft.fastq.gz >>{}_1sf.fastq",
-                    &args.input_sample_id, &args.input_sample_id
-                ))?;
-                // Capture the output of the second gunzip and append it to output_1sf_append
+        if config.split_reads {
+            let mut soft = vec![format!("{}_soft.fastq.gz", &config.sample_id)];
+            if !config.bwa_aligned_input {
+                soft.push(format!("{}_h1_soft.fastq.gz", &config.sample_id));
             }
+            gunzip(&soft, &format!("{}_1sf.fastq", &config.sample_id))?;
         }
 
-        if &args.sequencing_type == "paired-end" {
+        if config.paired() {
             println!("paird-end sequence type");
 
-            if !args.bwa_mem {
+            if !config.bwa_aligned_input {
                 // system("mv ${input_sampleID}_h1_h1_1.1fq ${input_sampleID}_1.1fq");
                 // system("mv ${input_sampleID}_h1_h1_2.1fq ${input_sampleID}_2.1fq");
                 move_files_fs(
-                    &format!("{}_h1_h1_1.1fq", &args.input_sample_id),
-                    &format!("{}_1.1fq", &args.input_sample_id),
+                    &format!("{}_h1_h1_1.1fq", &config.sample_id),
+                    &format!("{}_1.1fq", &config.sample_id),
                 )?;
                 move_files_fs(
-                    &format!("{}_h1_h1_2.1fq", &args.input_sample_id),
-                    &format!("{}_2.1fq", &args.input_sample_id),
+                    &format!("{}_h1_h1_2.1fq", &config.sample_id),
+                    &format!("{}_2.1fq", &config.sample_id),
                 )?;
             } else {
                 move_files_fs(
-                    &format!("{}_h1_1.1fq", &args.input_sample_id),
-                    &format!("{}_1.1fq", &args.input_sample_id),
+                    &format!("{}_h1_1.1fq", &config.sample_id),
+                    &format!("{}_1.1fq", &config.sample_id),
                 )?;
                 move_files_fs(
-                    &format!("{}_h1_2.1fq", &args.input_sample_id),
-                    &format!("{}_2.1fq", &args.input_sample_id),
+                    &format!("{}_h1_2.1fq", &config.sample_id),
+                    &format!("{}_2.1fq", &config.sample_id),
                 )?;
             }
         } else {
             println!("Not paird-end sequence type");
-            if !args.bwa_mem {
+            if !config.bwa_aligned_input {
                 move_files_fs(
-                    &format!("{}_h1_h1.1fq", &args.input_sample_id),
-                    &format!("{}.1fq", &args.input_sample_id),
+                    &format!("{}_h1_h1.1fq", &config.sample_id),
+                    &format!("{}.1fq", &config.sample_id),
                 )?;
             } else {
                 //bwa_MEM
                 move_files_fs(
-                    &format!("{}_h1.1fq", &args.input_sample_id),
-                    &format!("{}.1fq", &args.input_sample_id),
+                    &format!("{}_h1.1fq", &config.sample_id),
+                    &format!("{}.1fq", &config.sample_id),
                 )?;
             }
         }
-    } else {
-        order = 0;
-        align_to_hg(&args.input_sample_id, &args.file_suffix);
-        convert_bamtofastq(&args.input_sample_id);
-        if args.split.is_some() || &args.sequencing_type == "single-end" {
-            gunzip(&format!(
-                "{}_soft.fastq.gz >{}_1sf.fastq",
-                &args.input_sample_id, &args.input_sample_id
-            ))?;
-        }
-        if &args.sequencing_type == "paired-end" {
-            move_files_fs(
-                &format!("{}_h1_1.1fq", &args.input_sample_id),
-                &format!("{}_1.1fq", &args.input_sample_id),
-            )?;
-            move_files_fs(
-                &format!("{}_h1_2.1fq", &args.input_sample_id),
-                &format!("{}_2.1fq", &args.input_sample_id),
-            )?;
-        }
     }
 
     ////// Filter split reads
-    let sf1_file =
-        File::open(format!("{}_1sf.fastq", &args.input_sample_id)).expect("Failed to open file");
-    let sf2_file = File::create(format!("{}_1sf.fastq2", &args.input_sample_id))
-        .expect("Failed to create file for appending");
-
-    let mut reader = BufReader::new(sf1_file);
-    let mut writer = BufWriter::new(sf2_file);
-
-    let mut tmp1 = String::new();
-    while let Ok(bytes_read) = reader.read_line(&mut tmp1) {
-        if bytes_read == 0 {
-            break;
-        }
-
-        if tmp1.starts_with("@soft") {
-            let tmp1_parts: Vec<&str> = tmp1.split('|').collect();
-            if let Some(tmp1_third_part) = tmp1_parts.get(2) {
-                let tmp1_third_part_value =
-                    tmp1_third_part.trim().parse::<i32>().unwrap_or_default();
-                if tmp1_third_part_value % 4 >= 2 {
-                    // Handle single-end case if needed
-                    writer.write_all(tmp1.as_bytes())?;
-                    reader.read_line(&mut tmp1)?;
-                    writer.write_all(tmp1.as_bytes())?;
-                    reader.read_line(&mut tmp1)?;
-                    writer.write_all(tmp1.as_bytes())?;
-                    reader.read_line(&mut tmp1)?;
-                    writer.write_all(tmp1.as_bytes())?;
-                } else {
-                    reader.read_line(&mut tmp1)?;
-                    reader.read_line(&mut tmp1)?;
-                    reader.read_line(&mut tmp1)?;
-                }
-            }
-        }
-
-        tmp1.clear();
+    let sf = format!("{}_1sf.fastq", &config.sample_id);
+    if Path::new(&sf).exists() {
+        let (kept, total) = filter::filter_fastq(&sf, |record| config.split.accepts(record))?;
+        println!(
+            "~~~~~ kept {} of {} soft-clipped segments for the TE library",
+            kept, total
+        );
     }
-    move_files_fs(
-        &format!("{}_1sf.fastq2", &args.input_sample_id),
-        &format!("{}_1sf.fastq", &args.input_sample_id),
-    )?;
 
     // 2.3 Chimeric reads amd Split reads
     println!("\nChimeric and split reads...\n=====================================\n");
-    if &args.sequencing_type == "paired-end" {
-        // Command::new(format!("{}bwa",&bwa_d))
-        //     .arg(format!("mem -t {} -k 19 -r 1.5 -c 100000 -m 50 -T 30 -h 10000 -a -Y -M {} {}_1.1fq {}_2.1fq >{}_vsu.sam", &args.threads.unwrap_or(DEFAULT_THREADS),&args.te_reference_genome, &args.input_sample_id, &args.input_sample_id, &args.input_sample_id))
-        //     .output()?;
-        if let Err(err) = run_bwa_mem(&bwa_d, &format!("mem -t {} -k 19 -r 1.5 -c 100000 -m 50 -T 30 -h 10000 -a -Y -M {} {}_1.1fq {}_2.1fq >{}_vsu.sam", &args.threads.unwrap_or(DEFAULT_THREADS),&args.te_reference_genome, &args.input_sample_id, &args.input_sample_id, &args.input_sample_id)){
-            eprintln!("Error: Could not run bwa mem command >>> {}", err)
-        };
+    if config.paired() {
+        config
+            .te_aligner
+            .te_library(config.threads, config.chimeric_te_min_score)
+            .align_to_file(
+                &config.te_reference,
+                &input::fastq_reads(extracted, Path::new(&config.sample_id), "1fq"),
+                Path::new(&format!("{}_vsu.sam", &config.sample_id)),
+            )?;
     } else {
-        Command::new("touch")
-            .arg(format!("{}_vsu.sam", &args.input_sample_id))
-            .status()?;
-        //File::create(format!("{}_vsu.sam", &args.input_sample_id))?;
-    }
-    Command::new("touch")
-        .arg(format!("{}_all_breakpoint", &args.input_sample_id))
-        .status()?;
-    if &args.sequencing_type == "single-end"
-        || (sequencing_type == "paired-end" && args.split.is_some())
-    {
-        if let Err(err) = run_bwa_mem(&bwa_d, &format!("mem -t {} -k 19 -r 1.5 -c 100000 -m 50 -T 20 -h 10000 -a -Y -M {} {}_1sf.fastq >{}_vsoft.sam", &args.threads.unwrap_or(DEFAULT_THREADS),&args.te_reference_genome, &args.input_sample_id, &args.input_sample_id)){
-            eprintln!("Error: Could not run bwa mem command >>> {}", err)
-        };
-        if let Err(err) = run_any_system_cmdlet(
-            "perl",
-            &format!(
-                "{}Scripts/Soft_clipping_transfer.pl -f {}_vsoft.sam -o {}_vsoft_breakpoint",
-                &directory, &args.input_sample_id, &args.input_sample_id
-            ),
-        ) {
-            eprintln!("Error: Could not run perl command >>> {}", err)
-        }
-        if let Err(err) = run_any_system_cmdlet(
-            "cat",
-            &format!(
-                "{}_vsoft_breakpoint >>{}_all_breakpoint",
-                &args.input_sample_id, &args.input_sample_id
-            ),
-        ) {
-            eprintln!("Error: Could not run cat command >>> {}", err)
-        }
-        if let Err(err) =
-            run_any_system_cmdlet("rm", &format!("{}_vsoft_breakpoint", &args.input_sample_id))
-        {
-            eprintln!("Error: Could not run rm command >>> {}", err)
-        }
+        File::create(format!("{}_vsu.sam", &config.sample_id))?;
+    }
+    let mut evidence = Vec::new();
+    if config.split_reads {
+        config
+            .te_aligner
+            .te_library(config.threads, config.split_te_min_score)
+            .align_to_file(
+                &config.te_reference,
+                &Reads::Single(format!("{}_1sf.fastq", &config.sample_id).into()),
+                Path::new(&format!("{}_vsoft.sam", &config.sample_id)),
+            )?;
+        let vsoft = AlignmentReader::open(format!("{}_vsoft.sam", &config.sample_id), None)?;
+        let te_header = vsoft.header().clone();
+        let split = softclip::transfer_soft_clips(vsoft, &te_header)?;
+        println!("~~~~~ {} split reads hit the TE library", split.len());
+        evidence.extend(split);
     }
 
-    if &args.sequencing_type == "paired-end" {
-        if let Err(err) = run_any_system_cmdlet(
-            &format!("{}samtools", &samtools_d),
-            &format!(
-                "view {}_sm.bam >{}_sm.sam",
-                &args.input_sample_id, &args.input_sample_id
-            ),
-        ) {
-            eprintln!("Error: Could not run samtools command >>> {}", err)
-        }
+    if config.paired() {
+        let anchors = call_type(config)?;
+        let vsu = AlignmentReader::open(format!("{}_vsu.sam", &config.sample_id), None)?;
+        let te_header = vsu.header().clone();
+        let chimeric =
+            chimeric::call_chimeric(&anchors, vsu, &te_header, config.anchor.min_alignment_score)?;
+        println!(
+            "~~~~~ {} chimeric read pairs hit the TE library",
+            chimeric.len()
+        );
+        evidence.extend(chimeric);
+    }
 
-        //refactor the huge spaghetti to call_type func
-        let (sm_file_path, type_file_path) = call_type(&args, alignment_score)?;
+    filter::filter_intermediates(&config.sample_id, &evidence)?;
 
-        if let Err(err) = run_any_system_cmdlet("perl", &format!("{}Scripts/Break_point_calling.pl -type {} -position {} -TE {}_vsu.sam -alignment_score {} -o {}", &directory, &type_file_path, &sm_file_path, &args.input_sample_id, &alignment_score, &args.input_sample_id)){
-            eprintln!("Error: Could not run breakpoint calling script Err >>> {}", err);
-        }
-        if let Err(err) = run_any_system_cmdlet(
-            "cat",
-            &format!(
-                "{}_breakpoint >>{}_all_breakpoint",
-                &args.input_sample_id, &args.input_sample_id
-            ),
-        ) {
-            eprintln!("Error: Could not run command Err >>> {}", err);
-        }
-        if let Err(err) =
-            run_any_system_cmdlet("rm", &format!("{}_breakpoint", &args.input_sample_id))
-        {
-            eprintln!("Error: Could not run rm command >>> {}", err)
-        }
-    }
+    //##### 2.4 Improper Reads
+    println!("\nImproper reads...\n=====================================\n");
+    let all_breakpoint = format!("{}_all_breakpoint", &config.sample_id);
+    let mut all_breakpoint_out = BufWriter::new(File::create(&all_breakpoint)?);
+    breakpoint::write_breakpoints(&mut all_breakpoint_out, &evidence)?;
+    all_breakpoint_out.flush()?;
+    println!(
+        "~~~~~ {} supporting reads were written to {}",
+        evidence.len(),
+        all_breakpoint
+    );
+    // FASTQ input was aligned to <id>.bam by align_to_hg
+    let genotype_bam = match input {
+        SampleInput::Alignment { path, .. } => Some(path),
+        SampleInput::BamList(_) => None,
+        SampleInput::Fastq(_) => Some(PathBuf::from(format!("{}.bam", &config.sample_id))),
+    };
+    Ok(SampleEvidence {
+        breakpoints: evidence,
+        genotype_bam,
+    })
+}
+
+/// Calls and genotypes the insertions of a single sample, written to `<id>.output`
+/// and `<id>.vcf`.
+fn run_sample(config: &PipelineConfig) -> Result<()> {
+    let sample = extract_evidence(config)?;
+    let mut insertions = calling::call_insertions(sample.breakpoints, &config.calling);
 
-    //Run FilteredFastq perl script
-    if let Err(err) = run_any_system_cmdlet("perl", &format!("{}Scripts/Filtered_fastq.pl {}", &directory, &args.input_sample_id)){
-        eprintln!("Error: Could not run Filtered_fastq.pl script Err >>> {}", err);
+    //##### 2.5 Genotyping
+    if let Some(params) = &config.genotype {
+        println!("\nGenotyping...\n=====================================\n");
+        genotype_sample(
+            &config.sample_id,
+            sample.genotype_bam.as_deref(),
+            &mut insertions,
+            params,
+        )?;
     }
 
+    //##### 2.6 Output
+    let reference = open_reference(config);
+    if let Some(reference) = &reference {
+        vcf::sort_by_contig(&mut insertions, reference.contigs());
+    }
+    write_sample_output(config, &config.sample_id, &insertions)?;
 
-    //##### 2.4 Improper Reads
+    let vcf_file = format!("{}.vcf", &config.sample_id);
+    let mut vcf = VcfWriter::new(
+        BufWriter::new(File::create(&vcf_file)?),
+        reference,
+        &config.human_reference,
+        &[&config.sample_id],
+    )?;
+    for insertion in &insertions {
+        vcf.write_insertion(insertion, &[insertion.genotype.as_ref()])?;
+    }
+    vcf.finish()?;
+    println!("~~~~~ TE insertions were written to {}", vcf_file);
 
     Ok(())
 }
 
-fn call_type(args: &GetOptions, alignment_score: i32) -> Result<(String, String), Error> {
-    let sm_file_path = format!("{}_sm.sam", &args.input_sample_id);
-    let sm_file = File::create(&sm_file_path)?;
-    let type_file_path = format!("{}.type", &args.input_sample_id);
-    let type_file = File::create(&type_file_path)?;
-    let sm_reader = BufReader::new(sm_file);
-    let mut type_writer = LineWriter::new(type_file);
-    for sm_1 in sm_reader.lines() {
-        let sm_1 = sm_1?;
-        let sm_1_parts: Vec<&str> = sm_1.split_whitespace().collect();
-        let mut aS = "NA";
-        let mut xs = "NA";
-
-        if sm_1_parts[2] != "*" {
-            for i in 11..sm_1_parts.len() {
-                if let Some(captured) = sm_1_parts[i].strip_prefix("AS:i:") {
-                    aS = captured;
-                }
-                if let Some(captured) = sm_1_parts[i].strip_prefix("XS:i:") {
-                    xs = captured;
-                }
-            }
-        }
+/// Extracts the evidence of every sample of `--sample_sheet`, calls the insertions
+/// jointly and genotypes each sample at every site. Each sample's sites are written
+/// to `<sample>.output` and all samples to the multi-sample `<id>.vcf`, `-i` naming
+/// the cohort.
+fn run_cohort(config: &PipelineConfig) -> Result<()> {
+    let Some(sheet) = &config.sample_sheet else {
+        return Err(anyhow!("cohort mode needs a --sample_sheet"));
+    };
+    if config.bam.is_some() || config.fq1.is_some() {
+        return Err(anyhow!(
+            "cohort mode reads its input from the sample sheet, not --bam or --fq1"
+        ));
+    }
+    let samples = input::sample_sheet_ids(sheet)?;
+    let mut evidence = Vec::with_capacity(samples.len());
+    let mut genotype_bams = Vec::with_capacity(samples.len());
+    for sample_id in &samples {
+        println!("\n##### Sample {}", sample_id);
+        let sample = extract_evidence(&PipelineConfig {
+            sample_id: sample_id.clone(),
+            ..config.clone()
+        })?;
+        evidence.push(sample.breakpoints);
+        genotype_bams.push(sample.genotype_bam);
+    }
 
-        if aS == "NA" {
-            continue;
-        } else if sm_1_parts[1].parse::<i32>().unwrap() % 256 >= 128
-            && (aS.parse::<i32>().unwrap() >= alignment_score
-                && aS.parse::<i32>().unwrap() >= 2 * xs.parse::<i32>().unwrap())
-        {
-            writeln!(
-                type_writer,
-                "{} L {} {} {}\n",
-                sm_1_parts[0], aS, xs, sm_1_parts[5]
-            )?;
-        } else if aS.parse::<i32>().unwrap() >= alignment_score
-            && aS.parse::<i32>().unwrap() >= 2 * xs.parse::<i32>().unwrap()
+    println!("\nJoint calling...\n=====================================\n");
+    let mut calls = calling::call_joint_insertions(&evidence, &config.calling);
+    println!(
+        "~~~~~ {} candidate TE insertions were called across {} samples",
+        calls.sites.len(),
+        samples.len()
+    );
+
+    if let Some(params) = &config.genotype {
+        println!("\nGenotyping...\n=====================================\n");
+        for ((sample_id, bam), insertions) in
+            samples.iter().zip(&genotype_bams).zip(&mut calls.samples)
         {
-            writeln!(
-                type_writer,
-                "{} R {} {} {}\n",
-                sm_1_parts[0], aS, xs, sm_1_parts[5]
-            )?;
+            genotype_sample(sample_id, bam.as_deref(), insertions, params)?;
         }
     }
-    Ok((sm_file_path.to_owned(), type_file_path.to_owned()))
-}
 
-fn parse_as_xs(line: &str) -> (&str, &str) {
-    let mut aS = "NA";
-    let mut xs = "NA";
-
-    let parts: Vec<&str> = line.split_whitespace().collect();
+    let reference = open_reference(config);
+    if let Some(reference) = &reference {
+        calls.sort_by_contig(reference.contigs());
+    }
+    for (sample_id, insertions) in samples.iter().zip(&calls.samples) {
+        // sites the sample has no reads for and is not genotyped as carrying
+        let carried: Vec<Insertion> = insertions
+            .iter()
+            .filter(|i| {
+                i.supporting_reads() > 0
+                    || i.genotype.as_ref().is_some_and(|g| g.call != Call::HomRef)
+            })
+            .cloned()
+            .collect();
+        write_sample_output(config, sample_id, &carried)?;
+    }
 
-    if parts[2] != "*" {
-        for part in parts.iter().skip(11) {
-            if let Some(captured) = part.strip_prefix("AS:i:") {
-                aS = captured;
-            }
-            if let Some(captured) = part.strip_prefix("XS:i:") {
-                xs = captured;
-            }
+    let vcf_file = format!("{}.vcf", &config.sample_id);
+    let names: Vec<&str> = samples.iter().map(String::as_str).collect();
+    let mut vcf = VcfWriter::new(
+        BufWriter::new(File::create(&vcf_file)?),
+        reference,
+        &config.human_reference,
+        &names,
+    )?;
+    // population statistics need genotypes
+    let summary_file = format!("{}.population", &config.sample_id);
+    let mut summary = match config.genotype {
+        Some(_) => {
+            let mut out = BufWriter::new(File::create(&summary_file)?);
+            writeln!(out, "{}", population::SUMMARY_HEADER)?;
+            Some(out)
+        }
+        None => None,
+    };
+    for (i, site) in calls.sites.iter().enumerate() {
+        let genotypes: Vec<Option<&Genotype>> = calls
+            .samples
+            .iter()
+            .map(|insertions| insertions[i].genotype.as_ref())
+            .collect();
+        vcf.write_insertion(site, &genotypes)?;
+        if let Some(out) = &mut summary {
+            population::write_summary_row(out, site, &SiteStats::from_genotypes(&genotypes))?;
         }
     }
+    vcf.finish()?;
+    println!(
+        "~~~~~ TE insertions of {} samples were written to {}",
+        samples.len(),
+        vcf_file
+    );
+    if let Some(mut out) = summary {
+        out.flush()?;
+        println!(
+            "~~~~~ allele frequencies and Hardy-Weinberg tests were written to {}",
+            summary_file
+        );
+    }
 
-    (aS, xs)
+    Ok(())
 }
 
-fn run_bwa_mem(execution_dir: &str, command: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let status = Command::new(format!("{}bwa", execution_dir))
-        .arg(command)
-        .status()?;
+/// Genotypes `insertions` against the indexed `bam` of `sample_id`.
+fn genotype_sample(
+    sample_id: &str,
+    bam: Option<&Path>,
+    insertions: &mut [Insertion],
+    params: &GenotypeParams,
+) -> Result<()> {
+    let Some(bam) = bam else {
+        println!(
+            "~~~~~ genotyping is not available for a list of multiple BAM files ({})",
+            sample_id
+        );
+        return Ok(());
+    };
+    genotype::genotype_insertions(bam, &bai_path(bam), insertions, params)?;
+    println!(
+        "~~~~~ {} candidate TE insertions of {} were genotyped",
+        insertions.len(),
+        sample_id
+    );
+    Ok(())
+}
 
-    if status.success() {
-        println!("Command executed successfully");
-        Ok(())
-    } else {
-        eprintln!("Failed to execute command");
-        Err("Failed to execute command".into())
+/// The indexed human reference, used for REF bases and contig order in the VCF.
+fn open_reference(config: &PipelineConfig) -> Option<IndexedFasta> {
+    match IndexedFasta::open(&config.human_reference) {
+        Ok(reference) => Some(reference),
+        Err(err) => {
+            println!("~~~~~ the human reference genome is not indexed, REF bases are written as N ({:#})", err);
+            None
+        }
     }
 }
-fn run_any_system_cmdlet(program: &str, args: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let status = Command::new(program).arg(args).status()?;
 
-    if status.success() {
-        println!("Command executed successfully");
-        Ok(())
-    } else {
-        eprintln!("Failed to execute command");
-        Err("Failed to execute command".into())
-    }
+/// Writes the insertion table `<sample_id>.output`.
+fn write_sample_output(
+    config: &PipelineConfig,
+    sample_id: &str,
+    insertions: &[Insertion],
+) -> Result<()> {
+    let output_file = format!("{}.output", sample_id);
+    let mut output = BufWriter::new(File::create(&output_file)?);
+    calling::write_insertions(&mut output, sample_id, config.split_reads, insertions)?;
+    output.flush()?;
+    println!(
+        "~~~~~ {} candidate TE insertions were written to {}",
+        insertions.len(),
+        output_file
+    );
+    Ok(())
 }
-fn align_to_hg(format: &str, arg: &str) {
-    todo!()
+
+/// Classifies the anchor alignments in `<id>_sm.bam` into `<id>.type`.
+fn call_type(config: &PipelineConfig) -> Result<Vec<Anchor>> {
+    let sm_reader = AlignmentReader::open(format!("{}_sm.bam", &config.sample_id), None)?;
+    let header = sm_reader.header().clone();
+    let type_file_path = format!("{}.type", &config.sample_id);
+    let type_writer = BufWriter::new(File::create(&type_file_path)?);
+    let anchors = anchor::write_anchors(sm_reader, &header, &config.anchor, type_writer)?;
+    println!(
+        "~~~~~ {} anchor reads were written to {}",
+        anchors.len(),
+        type_file_path
+    );
+    Ok(anchors)
 }
 
-fn convert_bamtofastq(input_sample_id: &str) {
-    todo!()
+/// The index `<bam>.bai` next to `bam`.
+fn bai_path(bam: &Path) -> PathBuf {
+    let mut bai = bam.as_os_str().to_owned();
+    bai.push(".bai");
+    PathBuf::from(bai)
 }
 
-///execute mv system command
-/// @param = source, destination
-/// returns Result
-fn move_files_fs(source: &str, destination: &str) -> Result<()> {
-    match Command::new("mv").args(&[source, destination]).status() {
-        Ok(status) => {
-            if status.success() {
-                println!("Files moved successfully");
-            } else {
-                eprintln!("Failed to move files.");
-            }
-        }
-        Err(err) => {
-            eprintln!("Error: {}", err);
-        }
+/// Aligns `reads` to the human reference with the `--human_aligner`, writing
+/// `<name>.bam` to the working directory. With `index` the BAM is coordinate-sorted
+/// and indexed for genotyping, which needs `samtools`; otherwise it is written
+/// natively in the order of the aligner's output.
+fn align_to_hg(config: &PipelineConfig, reads: &Reads, name: &str, index: bool) -> Result<()> {
+    let threads = config.threads;
+    if let Some(missing) = reads.files().into_iter().find(|fq| !fq.exists()) {
+        return Err(anyhow!(
+            "could not find reads to align: {}",
+            missing.display()
+        ));
     }
+    let output = format!("{}.bam", name);
 
+    println!("~~~~~ aligning {} to the human reference genome", name);
+    let command = config
+        .human_aligner
+        .human(threads)
+        .command(&config.human_reference, reads);
+    if !index {
+        let alignments = SamPipe::spawn(&command)?;
+        let mut writer = BamWriter::create(&output, alignments.header())?;
+        for record in alignments {
+            writer.write_record(&record?)?;
+        }
+        writer.finish()?;
+        return Ok(());
+    }
+    command.pipe(&Cmd::new("samtools").args([
+        "sort",
+        "-@",
+        &threads.to_string(),
+        "-o",
+        &output,
+        "-",
+    ]))?;
+    Cmd::new("samtools").args(["index", &output]).run()?;
     Ok(())
 }
-///execute gunzip system command
-/// @param = file_pipe_args e.g file1.gz > file0
-/// returns Result
-fn gunzip(file_pipe_args: &str) -> Result<()> {
-    match Command::new("gunzip")
-        .arg("-c")
-        .arg(file_pipe_args)
-        .status()
-    {
-        Ok(status) => {
-            if status.success() {
-                println!("Files gunzipped successfully");
-            } else {
-                eprintln!("Failed to execute gunzip command.");
+
+/// Extracts the reads needed by the later steps from the alignment file `input`;
+/// see [`extract_reads`].
+fn convert_bamtofastq(config: &PipelineConfig, input: &Path, prefix: &str) -> Result<()> {
+    let reader = AlignmentReader::open(input, Some(&config.human_reference))?;
+    let header = reader.header().clone();
+    extract_reads(config, &header, reader, prefix)
+}
+
+/// Streams `records` and writes the reads needed by the later steps:
+/// `<prefix>_h1_1.1fq`/`<prefix>_h1_2.1fq` (`<prefix>_h1.1fq` for single-end) with the
+/// unmapped and discordant pairs, `<prefix>_soft.fastq.gz` with soft-clipped segments of
+/// at least `-S` bases, and `<prefix>_sm.bam`/`<prefix>_su.bam` with the mapped and
+/// unmapped reads of the extracted pairs.
+fn extract_reads<I>(
+    config: &PipelineConfig,
+    header: &Header,
+    records: I,
+    prefix: &str,
+) -> Result<()>
+where
+    I: IntoIterator<Item = io::Result<Record>>,
+{
+    let paired = config.paired();
+    let min_clip = config.split.min_clip_len;
+
+    let mut sm_writer = BamWriter::create(format!("{}_sm.bam", prefix), header)?;
+    let mut su_writer = BamWriter::create(format!("{}_su.bam", prefix), header)?;
+    let mut soft_writer = FastqWriter::create(format!("{}_soft.fastq.gz", prefix))?;
+    let (mut fq1, mut fq2) = if paired {
+        (
+            FastqWriter::create(format!("{}_h1_1.1fq", prefix))?,
+            Some(FastqWriter::create(format!("{}_h1_2.1fq", prefix))?),
+        )
+    } else {
+        (FastqWriter::create(format!("{}_h1.1fq", prefix))?, None)
+    };
+
+    // first-seen mate of each extracted pair, until the other mate turns up
+    let mut pending: HashMap<String, Record> = HashMap::new();
+    for record in records {
+        let record = record?;
+        if record.is_secondary_or_supplementary() {
+            continue;
+        }
+        if !record.is_unmapped() {
+            write_soft_clips(&mut soft_writer, header, &record, min_clip)?;
+        }
+
+        let Some(fq2) = fq2.as_mut() else {
+            if record.is_unmapped() {
+                fq1.write_record(&FastqRecord::from_alignment(&record))?;
+                su_writer.write_record(&record)?;
             }
+            continue;
+        };
+        let concordant = record.has_flag(flags::PROPER_PAIR)
+            && !record.has_flag(flags::UNMAPPED | flags::MATE_UNMAPPED);
+        if !record.has_flag(flags::PAIRED) || concordant {
+            continue;
         }
-        Err(err) => {
-            eprintln!("Error: {}", err);
+        if record.is_unmapped() {
+            su_writer.write_record(&record)?;
+        } else {
+            sm_writer.write_record(&record)?;
+        }
+        match pending.remove(&record.qname) {
+            Some(mate) => {
+                let (first, second) = if record.has_flag(flags::FIRST_IN_PAIR) {
+                    (&record, &mate)
+                } else {
+                    (&mate, &record)
+                };
+                fq1.write_record(&FastqRecord::from_alignment(first))?;
+                fq2.write_record(&FastqRecord::from_alignment(second))?;
+            }
+            None => {
+                pending.insert(record.qname.clone(), record);
+            }
         }
     }
+
+    if !pending.is_empty() {
+        println!(
+            "~~~~~ {} extracted reads of {} had no mate and were skipped",
+            pending.len(),
+            prefix
+        );
+    }
+    fq1.finish()?;
+    if let Some(fq2) = fq2 {
+        fq2.finish()?;
+    }
+    sm_writer.finish()?;
+    su_writer.finish()?;
+    soft_writer.finish()?;
+    Ok(())
+}
+
+/// Writes each soft-clipped end of at least `min_clip` bases as its own FASTQ record.
+/// The header is `@soft|<read>|<flag>|<chrom>|<breakpoint>|<L/R clip>|<cigar>`, where the
+/// breakpoint is the 1-based reference position of the aligned base next to the clip.
+fn write_soft_clips(
+    out: &mut FastqWriter,
+    header: &Header,
+    record: &Record,
+    min_clip: usize,
+) -> io::Result<()> {
+    let left = record.cigar.leading_soft_clip() as usize;
+    let right = record.cigar.trailing_soft_clip() as usize;
+    if record.seq.len() < left + right {
+        return Ok(());
+    }
+    let chrom = header.reference_name(record.ref_id);
+    let mut clips = Vec::new();
+    if left >= min_clip && left > 0 {
+        clips.push((ClipSide::Left, record.pos + 1, 0..left));
+    }
+    if right >= min_clip && right > 0 {
+        let breakpoint = record.pos + record.cigar.reference_len() as i32;
+        clips.push((
+            ClipSide::Right,
+            breakpoint,
+            record.seq.len() - right..record.seq.len(),
+        ));
+    }
+    for (side, breakpoint, range) in clips {
+        let clip = SoftClip {
+            read_id: record.qname.clone(),
+            flag: record.flag,
+            chrom: chrom.to_string(),
+            breakpoint: breakpoint as u32,
+            side,
+            cigar: record.cigar.to_string(),
+        };
+        let qual = record.qual.get(range.clone()).unwrap_or(&[]);
+        out.write_record(&FastqRecord {
+            name: clip.to_string(),
+            qual: fastq::phred_to_ascii(qual, range.len()),
+            seq: record.seq[range].to_vec(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Renames `source` to `destination`, replacing any existing file.
+fn move_files_fs(source: &str, destination: &str) -> Result<()> {
+    fs::rename(source, destination)
+        .with_context(|| format!("could not move {} to {}", source, destination))
+}
+
+/// Decompresses the gzip or BGZF files `sources`, in order, into `destination`.
+fn gunzip(sources: &[String], destination: &str) -> Result<()> {
+    let mut out = BufWriter::new(
+        File::create(destination).with_context(|| format!("could not create {}", destination))?,
+    );
+    bgzf::decompress_concat(sources, &mut out)
+        .with_context(|| format!("could not decompress into {}", destination))?;
+    out.flush()?;
     Ok(())
 }
 
-fn create_directory_if_not_exists(path: &str) -> Result<(), std::io::Error> {
-    if !Path::new(path).is_dir() {
+fn create_directory_if_not_exists(path: &Path) -> Result<(), std::io::Error> {
+    if !path.is_dir() {
         fs::create_dir(path)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::BamReader;
+    use crate::fastq::FastqReader;
+    use crate::sam::SamReader;
+    use crate::testdir::TempDir;
+
+    const SAM: &str = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:10000
+p1\t99\tchr1\t100\t60\t10M\t=\t300\t210\tACGTACGTAC\tIIIIIIIIII
+p1\t147\tchr1\t300\t60\t10M\t=\t100\t-210\tACGTACGTAC\tIIIIIIIIII
+u1\t133\tchr1\t200\t0\t*\t=\t200\t0\tGGGGGAAAAA\t##########
+d1\t65\tchr1\t1000\t60\t10M\t=\t8000\t0\tAAAAACCCCC\tABCDEFGHIJ
+u1\t73\tchr1\t200\t60\t10M\t=\t200\t0\tTTTTTCCCCC\tIIIIIIIIII
+d1\t145\tchr1\t8000\t60\t10M\t=\t1000\t0\tACGTTTTTTT\t0123456789
+s1\t99\tchr1\t500\t60\t5S10M5S\t=\t700\t210\tTTTTTACGTACGTACGGGGG\tIIIIIIIIIIIIIIIIIIII
+x1\t355\tchr1\t600\t0\t5S15M\t=\t700\t110\tCCCCCACGTACGTACGTACG\tIIIIIIIIIIIIIIIIIIII
+";
+
+    /// Runs [`extract_reads`] on [`SAM`], returning the output prefix.
+    fn extract(dir: &TempDir) -> String {
+        let config = Settings {
+            sample_id: Some("s1".to_string()),
+            human_reference: Some("hg38.fa".to_string()),
+            te_reference: Some("te.fa".to_string()),
+            min_clip_len: Some(5),
+            ..Settings::default()
+        }
+        .resolve()
+        .unwrap();
+        let reader = SamReader::new(SAM.as_bytes()).unwrap();
+        let header = reader.header().clone();
+        let prefix = dir.join("s1").to_string_lossy().into_owned();
+        extract_reads(&config, &header, reader, &prefix).unwrap();
+        prefix
+    }
+
+    fn fastq(path: String) -> Vec<(String, String, String)> {
+        FastqReader::open(path)
+            .unwrap()
+            .map(|record| {
+                let record = record.unwrap();
+                let text = |bytes: Vec<u8>| String::from_utf8(bytes).unwrap();
+                (record.name, text(record.seq), text(record.qual))
+            })
+            .collect()
+    }
+
+    fn bam_reads(path: String) -> Vec<(String, u16)> {
+        BamReader::from_path(path)
+            .unwrap()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.qname, record.flag)
+            })
+            .collect()
+    }
+
+    fn read(name: &str, seq: &str, qual: &str) -> (String, String, String) {
+        (name.to_string(), seq.to_string(), qual.to_string())
+    }
+
+    #[test]
+    fn extracted_pairs_are_written_in_mate_order() {
+        let dir = TempDir::new("extract");
+        let prefix = extract(&dir);
+        assert_eq!(
+            fastq(format!("{}_h1_1.1fq", prefix)),
+            [
+                read("u1", "TTTTTCCCCC", "IIIIIIIIII"),
+                read("d1", "AAAAACCCCC", "ABCDEFGHIJ"),
+            ]
+        );
+        // reverse-strand mates are turned back to their sequencing orientation
+        assert_eq!(
+            fastq(format!("{}_h1_2.1fq", prefix)),
+            [
+                read("u1", "GGGGGAAAAA", "##########"),
+                read("d1", "AAAAAAACGT", "9876543210"),
+            ]
+        );
+    }
+
+    #[test]
+    fn concordant_pairs_are_skipped_and_unmapped_reads_go_to_su() {
+        let dir = TempDir::new("extract");
+        let prefix = extract(&dir);
+        let reads = |reads: &[(&str, u16)]| -> Vec<(String, u16)> {
+            reads
+                .iter()
+                .map(|(name, flag)| (name.to_string(), *flag))
+                .collect()
+        };
+        assert_eq!(
+            bam_reads(format!("{}_sm.bam", prefix)),
+            reads(&[("d1", 65), ("u1", 73), ("d1", 145)])
+        );
+        assert_eq!(
+            bam_reads(format!("{}_su.bam", prefix)),
+            reads(&[("u1", 133)])
+        );
+    }
+
+    #[test]
+    fn soft_clips_of_both_sides() {
+        let dir = TempDir::new("extract");
+        let prefix = extract(&dir);
+        // the secondary alignment's clip is not used
+        assert_eq!(
+            fastq(format!("{}_soft.fastq.gz", prefix)),
+            [
+                read("soft|s1|99|chr1|500|L|5S10M5S", "TTTTT", "IIIII"),
+                read("soft|s1|99|chr1|509|R|5S10M5S", "GGGGG", "IIIII"),
+            ]
+        );
+    }
+}
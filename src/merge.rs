@@ -0,0 +1,345 @@
+//! Several alignment files of one sample, such as per-lane or per-chromosome
+//! BAMs, read as a single stream (`-m`).
+//!
+//! The files are read one after the other, so the stream is unsorted; mates
+//! split across files are still paired up by the read extraction. All files
+//! must share the reference dictionary of the first one. Read groups are kept
+//! per file: an `@RG` ID that another file uses for a different read group is
+//! renamed to `<ID>-<n>` in the header and in the records' `RG` tags, and a file
+//! without read groups gets one named after the file, with the sample as `SM`.
+
+use crate::alignment::AlignmentReader;
+use crate::bam::{Header, Record, Reference};
+use crate::tags::{self, AuxField, AuxValue};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The alignment files listed one per line in `list`. Blank lines and lines
+/// starting with `#` are skipped; relative paths are taken from the list's directory.
+pub fn read_bam_list(list: &Path) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(list)
+        .with_context(|| format!("could not read BAM list {}", list.display()))?;
+    let base = list.parent().unwrap_or(Path::new(""));
+    let paths: Vec<PathBuf> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect();
+    if paths.is_empty() {
+        return Err(anyhow!("{} lists no alignment files", list.display()));
+    }
+    Ok(paths)
+}
+
+/// How the records of one input have their `RG` tag rewritten.
+#[derive(Default)]
+struct ReadGroups {
+    /// Read group IDs of the file that were renamed in the merged header.
+    renamed: HashMap<String, String>,
+    /// The read group given to records of a file without `@RG` lines.
+    default: Option<String>,
+}
+
+impl ReadGroups {
+    fn apply(&self, record: &mut Record) -> io::Result<()> {
+        let current = match record.aux(b"RG") {
+            Some(AuxValue::String(id)) => Some(id),
+            _ => None,
+        };
+        let new = match (&current, &self.default) {
+            (None, Some(default)) => default,
+            (Some(id), _) => match self.renamed.get(id) {
+                Some(new) => new,
+                None => return Ok(()),
+            },
+            (None, None) => return Ok(()),
+        };
+        let mut aux = Vec::with_capacity(record.aux.len() + new.len() + 4);
+        for field in tags::fields(&record.aux) {
+            let field = field?;
+            if &field.tag != b"RG" {
+                field.encode(&mut aux);
+            }
+        }
+        AuxField {
+            tag: *b"RG",
+            value: AuxValue::String(new.clone()),
+        }
+        .encode(&mut aux);
+        record.aux = aux;
+        Ok(())
+    }
+}
+
+struct Input {
+    path: PathBuf,
+    reader: AlignmentReader,
+    groups: ReadGroups,
+}
+
+pub struct MergedReader {
+    header: Header,
+    inputs: Vec<Input>,
+    current: usize,
+}
+
+impl MergedReader {
+    /// Opens `paths` in order. `sample_id` names the sample of files without read
+    /// groups, and `reference` is the FASTA used to decode CRAM.
+    pub fn open(paths: &[PathBuf], sample_id: &str, reference: Option<&str>) -> Result<Self> {
+        let mut inputs = Vec::with_capacity(paths.len());
+        let mut references: Option<(&Path, Vec<Reference>)> = None;
+        let mut sq_lines = Vec::new();
+        let mut rg_lines: Vec<(String, String)> = Vec::new();
+        for (n, path) in paths.iter().enumerate() {
+            let reader = AlignmentReader::open(path, reference)?;
+            let header = reader.header();
+            match &references {
+                None => {
+                    references = Some((path, header.references.clone()));
+                    sq_lines = header_lines(&header.text, "@SQ").collect();
+                }
+                Some((first, refs)) => {
+                    if !same_references(refs, &header.references) {
+                        return Err(anyhow!(
+                            "{} does not have the reference sequences (@SQ) of {}",
+                            path.display(),
+                            first.display()
+                        ));
+                    }
+                }
+            }
+
+            let mut groups = ReadGroups::default();
+            let mut lines: Vec<String> = header_lines(&header.text, "@RG").collect();
+            if lines.is_empty() {
+                let stem = path.file_stem().unwrap_or(path.as_os_str());
+                let id = stem.to_string_lossy().into_owned();
+                lines.push(format!("@RG\tID:{}\tSM:{}", id, sample_id));
+                groups.default = Some(id);
+            }
+            for line in lines {
+                let id = read_group_id(&line)
+                    .with_context(|| format!("{}: @RG line without ID", path.display()))?
+                    .to_string();
+                let synthesized = groups.default.as_ref() == Some(&id);
+                match rg_lines.iter().find(|(seen, _)| *seen == id) {
+                    // an identical @RG line is the same read group, unless it was made
+                    // up for another file without read groups that has the same name
+                    Some((_, seen_line)) if *seen_line == line && !synthesized => continue,
+                    Some(_) => {
+                        let mut k = n + 1;
+                        let new_id = loop {
+                            let candidate = format!("{}-{}", id, k);
+                            if !rg_lines.iter().any(|(seen, _)| *seen == candidate) {
+                                break candidate;
+                            }
+                            k += 1;
+                        };
+                        let line =
+                            line.replacen(&format!("\tID:{}", id), &format!("\tID:{}", new_id), 1);
+                        if synthesized {
+                            groups.default = Some(new_id.clone());
+                        } else {
+                            groups.renamed.insert(id, new_id.clone());
+                        }
+                        rg_lines.push((new_id, line));
+                    }
+                    None => rg_lines.push((id, line)),
+                }
+            }
+            inputs.push(Input {
+                path: path.clone(),
+                reader,
+                groups,
+            });
+        }
+        let Some((_, references)) = references else {
+            return Err(anyhow!("no alignment files to merge"));
+        };
+
+        let mut text = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+        for line in sq_lines.iter().chain(rg_lines.iter().map(|(_, line)| line)) {
+            text.push_str(line);
+            text.push('\n');
+        }
+        Ok(MergedReader {
+            header: Header { text, references },
+            inputs,
+            current: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
+impl Iterator for MergedReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(input) = self.inputs.get_mut(self.current) {
+            let record = match input.reader.next() {
+                Some(Ok(mut record)) => input.groups.apply(&mut record).map(|()| record),
+                Some(Err(err)) => Err(err),
+                None => {
+                    self.current += 1;
+                    continue;
+                }
+            };
+            let path = &input.path;
+            return Some(
+                record.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+            );
+        }
+        None
+    }
+}
+
+/// The header lines of `text` with record type `kind`, such as `@RG`.
+fn header_lines<'a>(text: &'a str, kind: &'a str) -> impl Iterator<Item = String> + 'a {
+    text.lines()
+        .filter(move |line| line.split('\t').next() == Some(kind))
+        .map(str::to_string)
+}
+
+fn read_group_id(line: &str) -> Option<&str> {
+    line.split('\t').find_map(|field| field.strip_prefix("ID:"))
+}
+
+fn same_references(a: &[Reference], b: &[Reference]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.name == b.name && a.length == b.length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    const SQ: &str = "@SQ\tSN:chr1\tLN:1000\n";
+
+    /// SAM text with unmapped `reads`, each a read name followed by its
+    /// tab-separated tags, if any.
+    fn sam(header: &str, reads: &[&str]) -> String {
+        let mut text = format!("@HD\tVN:1.6\n{}", header);
+        for read in reads {
+            let (name, tags) = match read.split_once('\t') {
+                Some((name, tags)) => (name, format!("\t{}", tags)),
+                None => (*read, String::new()),
+            };
+            text.push_str(&format!(
+                "{}\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII{}\n",
+                name, tags
+            ));
+        }
+        text
+    }
+
+    /// Record names with their `RG` tag.
+    type Tagged = Vec<(String, String)>;
+
+    /// The merged header's `@RG` lines and each record's name with its `RG` tag.
+    fn merge(dir: &TempDir, files: &[&str]) -> Result<(Vec<String>, Tagged)> {
+        let paths: Vec<PathBuf> = files.iter().map(|file| dir.join(file)).collect();
+        let reader = MergedReader::open(&paths, "s1", None)?;
+        let groups = header_lines(&reader.header().text, "@RG").collect();
+        let mut records = Vec::new();
+        for record in reader {
+            let record = record?;
+            let rg = match record.aux(b"RG") {
+                Some(AuxValue::String(id)) => id,
+                other => panic!("{} has RG {:?}", record.qname, other),
+            };
+            records.push((record.qname, rg));
+        }
+        Ok((groups, records))
+    }
+
+    fn pairs(records: &[(&str, &str)]) -> Tagged {
+        records
+            .iter()
+            .map(|(name, rg)| (name.to_string(), rg.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn clashing_read_groups_are_renamed() {
+        let dir = TempDir::new("merge");
+        fs::write(
+            dir.join("a.sam"),
+            sam(
+                &format!("{}@RG\tID:rg1\tSM:s1\tLB:a\n", SQ),
+                &["r1\tRG:Z:rg1"],
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.sam"),
+            sam(
+                &format!("{}@RG\tID:rg1\tSM:s1\tLB:b\n@RG\tID:rg2\tSM:s1\n", SQ),
+                &["r2\tRG:Z:rg1", "r3\tRG:Z:rg2"],
+            ),
+        )
+        .unwrap();
+        let (groups, records) = merge(&dir, &["a.sam", "b.sam"]).unwrap();
+        assert_eq!(
+            groups,
+            [
+                "@RG\tID:rg1\tSM:s1\tLB:a",
+                "@RG\tID:rg1-2\tSM:s1\tLB:b",
+                "@RG\tID:rg2\tSM:s1"
+            ]
+        );
+        assert_eq!(
+            records,
+            pairs(&[("r1", "rg1"), ("r2", "rg1-2"), ("r3", "rg2")])
+        );
+    }
+
+    #[test]
+    fn identical_read_groups_are_shared() {
+        let dir = TempDir::new("merge");
+        let header = format!("{}@RG\tID:rg1\tSM:s1\n", SQ);
+        fs::write(dir.join("a.sam"), sam(&header, &["r1\tRG:Z:rg1"])).unwrap();
+        fs::write(dir.join("b.sam"), sam(&header, &["r2\tRG:Z:rg1"])).unwrap();
+        let (groups, records) = merge(&dir, &["a.sam", "b.sam"]).unwrap();
+        assert_eq!(groups, ["@RG\tID:rg1\tSM:s1"]);
+        assert_eq!(records, pairs(&[("r1", "rg1"), ("r2", "rg1")]));
+    }
+
+    #[test]
+    fn files_without_read_groups_get_one_each() {
+        let dir = TempDir::new("merge");
+        fs::create_dir_all(dir.join("L001")).unwrap();
+        fs::create_dir_all(dir.join("L002")).unwrap();
+        fs::write(dir.join("L001/s1.sam"), sam(SQ, &["r1"])).unwrap();
+        fs::write(dir.join("L002/s1.sam"), sam(SQ, &["r2"])).unwrap();
+        let (groups, records) = merge(&dir, &["L001/s1.sam", "L002/s1.sam"]).unwrap();
+        assert_eq!(groups, ["@RG\tID:s1\tSM:s1", "@RG\tID:s1-2\tSM:s1"]);
+        assert_eq!(records, pairs(&[("r1", "s1"), ("r2", "s1-2")]));
+    }
+
+    #[test]
+    fn mismatched_references_are_rejected() {
+        let dir = TempDir::new("merge");
+        fs::write(dir.join("a.sam"), sam(SQ, &[])).unwrap();
+        fs::write(dir.join("b.sam"), sam("@SQ\tSN:chr1\tLN:2000\n", &[])).unwrap();
+        let err = merge(&dir, &["a.sam", "b.sam"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} does not have the reference sequences (@SQ) of {}",
+                dir.join("b.sam").display(),
+                dir.join("a.sam").display()
+            )
+        );
+    }
+}
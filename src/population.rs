@@ -0,0 +1,221 @@
+//! Population statistics of genotyped insertions across the samples of a cohort:
+//! allele counts and frequency, call rate and the Hardy-Weinberg exact test.
+
+use crate::calling::Insertion;
+use crate::genotype::{Call, Genotype};
+use anyhow::Result;
+use std::io::Write;
+
+/// Header of the cohort summary table.
+pub const SUMMARY_HEADER: &str = "Chrom\tPosition\tTE_family\tStrand\tSamples\tCalled\tCall_rate\tHom_ref\tHet\tHom_alt\tAC\tAN\tAF\tHWE_p";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SiteStats {
+    pub samples: u32,
+    pub hom_ref: u32,
+    pub het: u32,
+    pub hom_alt: u32,
+}
+
+impl SiteStats {
+    /// Counts the calls of one site; samples without a genotype are uncalled.
+    pub fn from_genotypes(genotypes: &[Option<&Genotype>]) -> Self {
+        let mut stats = SiteStats {
+            samples: genotypes.len() as u32,
+            hom_ref: 0,
+            het: 0,
+            hom_alt: 0,
+        };
+        for genotype in genotypes.iter().flatten() {
+            match genotype.call {
+                Call::HomRef => stats.hom_ref += 1,
+                Call::Het => stats.het += 1,
+                Call::HomAlt => stats.hom_alt += 1,
+            }
+        }
+        stats
+    }
+
+    pub fn called(&self) -> u32 {
+        self.hom_ref + self.het + self.hom_alt
+    }
+
+    /// Insertion alleles among the called genotypes.
+    pub fn allele_count(&self) -> u32 {
+        self.het + 2 * self.hom_alt
+    }
+
+    /// Called alleles, two per diploid genotype.
+    pub fn allele_number(&self) -> u32 {
+        2 * self.called()
+    }
+
+    /// Insertion allele frequency, `None` without calls.
+    pub fn allele_frequency(&self) -> Option<f64> {
+        let an = self.allele_number();
+        (an > 0).then(|| f64::from(self.allele_count()) / f64::from(an))
+    }
+
+    pub fn call_rate(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            f64::from(self.called()) / f64::from(self.samples)
+        }
+    }
+
+    /// Hardy-Weinberg exact test p-value, `None` without calls.
+    pub fn hwe_p(&self) -> Option<f64> {
+        (self.called() > 0).then(|| hwe_exact(self.het, self.hom_ref, self.hom_alt))
+    }
+}
+
+/// Two-sided Hardy-Weinberg exact test of Wigginton, Cutler and Abecasis (2005):
+/// the total probability of all heterozygote counts, given the allele counts, that
+/// are no more likely than the one observed.
+pub fn hwe_exact(het: u32, hom_ref: u32, hom_alt: u32) -> f64 {
+    let n = (het + hom_ref + hom_alt) as usize;
+    if n == 0 {
+        return 1.0;
+    }
+    let obs_het = het as usize;
+    let rare = 2 * hom_ref.min(hom_alt) as usize + obs_het;
+    let common = 2 * n - rare;
+
+    // heterozygote counts have the parity of the rare allele count; start from
+    // the most likely one and fill both directions with the recurrence
+    let mut probs = vec![0.0; rare + 1];
+    let mut mid = rare * common / (2 * n);
+    if mid % 2 != rare % 2 {
+        mid += 1;
+    }
+    probs[mid] = 1.0;
+    let mut sum = 1.0;
+
+    let (mut hets, mut hom_r, mut hom_c) = (mid, (rare - mid) / 2, (common - mid) / 2);
+    while hets > 1 {
+        let p = probs[hets] * (hets * (hets - 1)) as f64
+            / (4.0 * (hom_r + 1) as f64 * (hom_c + 1) as f64);
+        probs[hets - 2] = p;
+        sum += p;
+        hets -= 2;
+        hom_r += 1;
+        hom_c += 1;
+    }
+    let (mut hets, mut hom_r, mut hom_c) = (mid, (rare - mid) / 2, (common - mid) / 2);
+    while hets + 2 <= rare {
+        let p = probs[hets] * (4 * hom_r * hom_c) as f64 / ((hets + 2) * (hets + 1)) as f64;
+        probs[hets + 2] = p;
+        sum += p;
+        hets += 2;
+        hom_r -= 1;
+        hom_c -= 1;
+    }
+
+    let observed = probs[obs_het];
+    let p: f64 = probs.iter().filter(|&&p| p <= observed).sum::<f64>() / sum;
+    p.min(1.0)
+}
+
+/// Formats a probability with four decimals, or in scientific notation when small.
+pub fn format_probability(p: f64) -> String {
+    if p == 0.0 || p >= 0.001 {
+        format!("{:.4}", p)
+    } else {
+        format!("{:.3e}", p)
+    }
+}
+
+/// Writes one line of the cohort summary table.
+pub fn write_summary_row<W: Write>(
+    out: &mut W,
+    insertion: &Insertion,
+    stats: &SiteStats,
+) -> Result<()> {
+    let optional = |p: Option<f64>| p.map_or_else(|| "NA".to_string(), format_probability);
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        insertion.chrom,
+        insertion.position,
+        insertion.te_family,
+        insertion.strand,
+        stats.samples,
+        stats.called(),
+        stats.call_rate(),
+        stats.hom_ref,
+        stats.het,
+        stats.hom_alt,
+        stats.allele_count(),
+        stats.allele_number(),
+        optional(stats.allele_frequency()),
+        optional(stats.hwe_p())
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genotype(call: Call) -> Genotype {
+        Genotype {
+            call,
+            ref_reads: 0,
+            alt_reads: 0,
+            pl: [0; 3],
+            gq: 0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected * 1e-3,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn hwe_exact_matches_published_values() {
+        // 57/14/29 is the worked example of Wigginton et al. (2005); the other values
+        // agree with summing the exact probabilities of every heterozygote count
+        assert_close(hwe_exact(57, 14, 29), 0.1507);
+        assert_close(hwe_exact(0, 10, 10), 1.340e-6);
+        assert_close(hwe_exact(10, 0, 0), 0.006_9);
+        assert_close(hwe_exact(3, 5, 2), 0.4799);
+        assert_close(hwe_exact(2, 90, 8), 1.675e-10);
+    }
+
+    #[test]
+    fn hwe_exact_is_one_for_monomorphic_sites() {
+        assert_eq!(hwe_exact(0, 0, 0), 1.0);
+        assert_eq!(hwe_exact(0, 20, 0), 1.0);
+        assert_eq!(hwe_exact(0, 0, 7), 1.0);
+    }
+
+    #[test]
+    fn missing_samples_are_not_called() {
+        let het = genotype(Call::Het);
+        let hom_alt = genotype(Call::HomAlt);
+        let hom_ref = genotype(Call::HomRef);
+        let stats =
+            SiteStats::from_genotypes(&[Some(&het), None, Some(&hom_alt), None, Some(&hom_ref)]);
+        assert_eq!(stats.samples, 5);
+        assert_eq!(stats.called(), 3);
+        assert_eq!(stats.allele_count(), 3);
+        assert_eq!(stats.allele_number(), 6);
+        assert_eq!(stats.allele_frequency(), Some(0.5));
+        assert_eq!(stats.call_rate(), 0.6);
+    }
+
+    #[test]
+    fn site_without_calls() {
+        let stats = SiteStats::from_genotypes(&[None, None]);
+        assert_eq!((stats.called(), stats.allele_number()), (0, 0));
+        assert_eq!(stats.allele_frequency(), None);
+        assert_eq!(stats.hwe_p(), None);
+        assert_eq!(stats.call_rate(), 0.0);
+    }
+}
@@ -0,0 +1,206 @@
+//! SAM text reading into the same [`Record`] type as BAM.
+
+use crate::bam::{reg2bin, Cigar, Header, Record, Reference};
+use crate::bgzf::invalid_data;
+use crate::tags::AuxField;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+pub struct SamReader<R: BufRead> {
+    inner: R,
+    header: Header,
+    ref_ids: HashMap<String, i32>,
+    line: String,
+    /// First alignment line, consumed while reading the header.
+    pending: Option<String>,
+}
+
+impl<R: BufRead> SamReader<R> {
+    /// Reads the `@` header lines; the reference dictionary comes from `@SQ`.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = Header::default();
+        let mut pending = None;
+        let mut line = String::new();
+        while inner.read_line(&mut line)? > 0 {
+            if !line.starts_with('@') {
+                pending = Some(std::mem::take(&mut line));
+                break;
+            }
+            if line.starts_with("@SQ") {
+                let mut reference = Reference::default();
+                for field in line.trim_end().split('\t').skip(1) {
+                    if let Some(name) = field.strip_prefix("SN:") {
+                        reference.name = name.to_string();
+                    } else if let Some(length) = field.strip_prefix("LN:") {
+                        reference.length = length
+                            .parse()
+                            .map_err(|_| invalid_data("invalid @SQ length"))?;
+                    }
+                }
+                header.references.push(reference);
+            }
+            header.text.push_str(&line);
+            line.clear();
+        }
+        let ref_ids = header
+            .references
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (r.name.clone(), i as i32))
+            .collect();
+        Ok(SamReader {
+            inner,
+            header,
+            ref_ids,
+            line,
+            pending,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Reads the next alignment line, returning `None` at the end of the input.
+    pub fn read_record(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            match self.pending.take() {
+                Some(line) => self.line = line,
+                None => {
+                    self.line.clear();
+                    if self.inner.read_line(&mut self.line)? == 0 {
+                        return Ok(None);
+                    }
+                }
+            }
+            let line = std::mem::take(&mut self.line);
+            if !line.trim().is_empty() {
+                return self
+                    .parse_record(line.trim_end_matches(['\n', '\r']))
+                    .map(Some);
+            }
+        }
+    }
+
+    /// Reference id for a name, which must be in the `@SQ` dictionary: the header
+    /// is handed out before the records, so it cannot grow while reading.
+    fn ref_id(&self, name: &str) -> io::Result<i32> {
+        if name == "*" {
+            return Ok(-1);
+        }
+        self.ref_ids.get(name).copied().ok_or_else(|| {
+            invalid_data(&format!(
+                "reference sequence '{}' is not in the @SQ header lines",
+                name
+            ))
+        })
+    }
+
+    fn parse_record(&self, line: &str) -> io::Result<Record> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 11 {
+            return Err(invalid_data(&format!(
+                "SAM line has {} fields, expected at least 11",
+                fields.len()
+            )));
+        }
+        let number = |i: usize| -> io::Result<i64> {
+            fields[i]
+                .parse()
+                .map_err(|_| invalid_data(&format!("invalid SAM field '{}'", fields[i])))
+        };
+        let ref_id = self.ref_id(fields[2])?;
+        let next_ref_id = match fields[6] {
+            "=" => ref_id,
+            name => self.ref_id(name)?,
+        };
+        let cigar: Cigar = fields[5].parse()?;
+        let pos = number(3)? as i32 - 1;
+        let seq: Vec<u8> = match fields[9] {
+            "*" => Vec::new(),
+            s => s.as_bytes().to_vec(),
+        };
+        let qual = match fields[10] {
+            "*" => vec![0xff; seq.len()],
+            q => q.bytes().map(|b| b.saturating_sub(33)).collect(),
+        };
+        let mut aux = Vec::new();
+        for field in &fields[11..] {
+            field.parse::<AuxField>()?.encode(&mut aux);
+        }
+        let end = if cigar.0.is_empty() {
+            pos + 1
+        } else {
+            pos + cigar.reference_len().max(1) as i32
+        };
+        Ok(Record {
+            qname: fields[0].to_string(),
+            flag: number(1)? as u16,
+            ref_id,
+            pos,
+            mapq: number(4)? as u8,
+            bin: reg2bin(pos.max(0), end.max(1)),
+            cigar,
+            next_ref_id,
+            next_pos: number(7)? as i32 - 1,
+            tlen: number(8)? as i32,
+            seq,
+            qual,
+            aux,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for SamReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n";
+
+    fn records(body: &str) -> io::Result<Vec<Record>> {
+        let sam = format!("{}{}", HEADER, body);
+        SamReader::new(sam.as_bytes())?.collect()
+    }
+
+    #[test]
+    fn contigs_come_from_the_header() {
+        let records = records(
+            "r1\t97\tchr2\t10\t60\t4M\t=\t50\t44\tACGT\tIIII\nr2\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n",
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                records[0].ref_id,
+                records[0].pos,
+                records[0].next_ref_id,
+                records[0].next_pos
+            ),
+            (1, 9, 1, 49)
+        );
+        assert_eq!((records[1].ref_id, records[1].next_ref_id), (-1, -1));
+        assert_eq!(records[1].qual, [] as [u8; 0]);
+    }
+
+    #[test]
+    fn unknown_contigs_are_rejected() {
+        for body in [
+            "r1\t0\tchrX\t10\t60\t4M\t*\t0\t0\tACGT\tIIII\n",
+            "r1\t1\tchr1\t10\t60\t4M\tchrX\t50\t0\tACGT\tIIII\n",
+        ] {
+            let err = records(body).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(
+                err.to_string(),
+                "reference sequence 'chrX' is not in the @SQ header lines"
+            );
+        }
+    }
+}
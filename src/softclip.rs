@@ -0,0 +1,405 @@
+//! Soft-clipped segments and the transfer of their TE alignments into split-read
+//! breakpoints.
+//!
+//! Each segment is written to `<id>_soft.fastq.gz` under a header
+//! `@soft|read_id|flag|chrom|breakpoint|side|cigar` describing the human alignment
+//! it was clipped from: `breakpoint` is the 1-based aligned base next to the clip,
+//! and `side` is `L` for a clip at the start of the alignment and `R` at its end.
+
+use crate::bam::{flags, Header, Record};
+use crate::breakpoint::{Breakpoint, Evidence, Flank, Strand};
+use crate::fastq::FastqRecord;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipSide {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoftClip {
+    pub read_id: String,
+    /// SAM flag of the human alignment.
+    pub flag: u16,
+    pub chrom: String,
+    pub breakpoint: u32,
+    pub side: ClipSide,
+    /// CIGAR of the human alignment.
+    pub cigar: String,
+}
+
+impl SoftClip {
+    /// The insertion flank the aligned part of the read lies on: a clip at the
+    /// start of the alignment leaves the human sequence right of the insertion.
+    pub fn flank(&self) -> Flank {
+        match self.side {
+            ClipSide::Left => Flank::Right,
+            ClipSide::Right => Flank::Left,
+        }
+    }
+
+    pub fn strand(&self) -> Strand {
+        if self.flag & flags::REVERSE != 0 {
+            Strand::Reverse
+        } else {
+            Strand::Forward
+        }
+    }
+}
+
+impl fmt::Display for ClipSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClipSide::Left => "L",
+            ClipSide::Right => "R",
+        })
+    }
+}
+
+impl FromStr for ClipSide {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "L" => Ok(ClipSide::Left),
+            "R" => Ok(ClipSide::Right),
+            _ => Err(anyhow!("unknown clip side '{}'", s)),
+        }
+    }
+}
+
+/// The header without the leading `@`, as aligners report it in QNAME.
+impl fmt::Display for SoftClip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "soft|{}|{}|{}|{}|{}|{}",
+            self.read_id, self.flag, self.chrom, self.breakpoint, self.side, self.cigar
+        )
+    }
+}
+
+impl FromStr for SoftClip {
+    type Err = anyhow::Error;
+
+    /// Parses a header with or without the leading `@`. Fields are taken from the
+    /// right so that read ids containing `|` survive.
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.trim_start_matches('@');
+        let fields: Vec<&str> = name.rsplitn(6, '|').collect();
+        let [cigar, side, breakpoint, chrom, flag, head] = fields[..] else {
+            return Err(anyhow!("'{}' is not a soft-clip header", s));
+        };
+        let read_id = head
+            .strip_prefix("soft|")
+            .ok_or_else(|| anyhow!("'{}' is not a soft-clip header", s))?;
+        Ok(SoftClip {
+            read_id: read_id.to_string(),
+            flag: flag.parse()?,
+            chrom: chrom.to_string(),
+            breakpoint: breakpoint.parse()?,
+            side: side.parse()?,
+            cigar: cigar.to_string(),
+        })
+    }
+}
+
+/// Which soft-clipped segments are aligned to the TE library.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitReadPolicy {
+    /// Keep only clips on this side of the alignment; `None` keeps both.
+    pub side: Option<ClipSide>,
+    pub min_clip_len: usize,
+    /// Minimum mean phred quality of the clipped bases.
+    pub min_mean_quality: f64,
+    /// Largest fraction of the clip a single base may make up, which drops
+    /// poly-A tails and other low-complexity sequence.
+    pub max_base_fraction: f64,
+    /// Require the human alignment to be a proper pair.
+    pub proper_pair: bool,
+}
+
+impl SplitReadPolicy {
+    /// Whether the FASTQ record of a soft-clipped segment passes. Records whose
+    /// name is not a soft-clip header never do.
+    pub fn accepts(&self, record: &FastqRecord) -> bool {
+        let Ok(clip) = record.name.parse::<SoftClip>() else {
+            return false;
+        };
+        if self.side.is_some_and(|side| side != clip.side)
+            || record.seq.len() < self.min_clip_len
+            || (self.proper_pair && clip.flag & flags::PROPER_PAIR == 0)
+        {
+            return false;
+        }
+        if !record.qual.is_empty() {
+            let sum: u64 = record
+                .qual
+                .iter()
+                .map(|q| q.saturating_sub(33) as u64)
+                .sum();
+            if (sum as f64) / (record.qual.len() as f64) < self.min_mean_quality {
+                return false;
+            }
+        }
+        base_fraction(&record.seq) <= self.max_base_fraction
+    }
+}
+
+/// Fraction of `seq` made up by its most common base, ignoring case.
+fn base_fraction(seq: &[u8]) -> f64 {
+    if seq.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for b in seq {
+        counts[b.to_ascii_uppercase() as usize] += 1;
+    }
+    let max = counts.iter().max().copied().unwrap_or(0);
+    max as f64 / seq.len() as f64
+}
+
+/// Turns the primary TE alignments of soft-clipped segments into split-read
+/// breakpoints. Segments keep the genome orientation of their read, so the TE
+/// strand is taken from the alignment as is.
+pub fn transfer_soft_clips<I>(records: I, header: &Header) -> Result<Vec<Breakpoint>>
+where
+    I: IntoIterator<Item = io::Result<Record>>,
+{
+    let mut breakpoints = Vec::new();
+    for record in records {
+        let record = record?;
+        if record.is_unmapped() || record.is_secondary_or_supplementary() {
+            continue;
+        }
+        let clip: SoftClip = record.qname.parse().with_context(|| {
+            format!("unexpected read '{}' in soft-clip alignments", record.qname)
+        })?;
+        breakpoints.push(Breakpoint {
+            evidence: Evidence::Split,
+            strand: clip.strand(),
+            flank: clip.flank(),
+            position: clip.breakpoint,
+            read_id: clip.read_id,
+            chrom: clip.chrom,
+            te_name: header.reference_name(record.ref_id).to_string(),
+            te_position: (record.pos + 1) as u32,
+            te_strand: if record.is_reverse() {
+                Strand::Reverse
+            } else {
+                Strand::Forward
+            },
+        });
+    }
+    Ok(breakpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sam::SamReader;
+
+    fn clip(read_id: &str, side: ClipSide) -> SoftClip {
+        SoftClip {
+            read_id: read_id.to_string(),
+            flag: 99,
+            chrom: "chr1".to_string(),
+            breakpoint: 1000,
+            side,
+            cigar: "30S70M".to_string(),
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = "soft|r1|99|chr1|1000|L|30S70M";
+        assert_eq!(clip("r1", ClipSide::Left).to_string(), header);
+        assert_eq!(
+            format!("@{}", header).parse::<SoftClip>().unwrap(),
+            clip("r1", ClipSide::Left)
+        );
+        for clip in [clip("r1/1", ClipSide::Right), clip("a|b|c", ClipSide::Left)] {
+            assert_eq!(clip.to_string().parse::<SoftClip>().unwrap(), clip);
+        }
+    }
+
+    #[test]
+    fn pipes_in_read_ids_survive() {
+        let clip: SoftClip = "soft|run1|lane|7|16|chrX|52|R|70M30S".parse().unwrap();
+        assert_eq!(clip.read_id, "run1|lane|7");
+        assert_eq!((clip.flag, clip.chrom.as_str()), (16, "chrX"));
+        assert_eq!((clip.breakpoint, clip.side), (52, ClipSide::Right));
+        assert_eq!(clip.strand(), Strand::Reverse);
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        for header in [
+            "r1",
+            "soft|r1|99|chr1|1000|L",
+            "hard|r1|99|chr1|1000|L|30S70M",
+            "soft|r1|99|chr1|1000|X|30S70M",
+            "soft|r1|flag|chr1|1000|L|30S70M",
+        ] {
+            assert!(header.parse::<SoftClip>().is_err(), "{}", header);
+        }
+    }
+
+    #[test]
+    fn clip_side_is_the_opposite_flank() {
+        assert_eq!(clip("r1", ClipSide::Left).flank(), Flank::Right);
+        assert_eq!(clip("r1", ClipSide::Right).flank(), Flank::Left);
+    }
+
+    fn policy() -> SplitReadPolicy {
+        SplitReadPolicy {
+            side: None,
+            min_clip_len: 10,
+            min_mean_quality: 20.0,
+            max_base_fraction: 0.8,
+            proper_pair: false,
+        }
+    }
+
+    fn segment(flag: u16, side: ClipSide, seq: &str, qual: u8) -> FastqRecord {
+        let mut clip = clip("r1", side);
+        clip.flag = flag;
+        FastqRecord {
+            name: clip.to_string(),
+            seq: seq.as_bytes().to_vec(),
+            qual: vec![qual + 33; seq.len()],
+        }
+    }
+
+    const SEQ: &str = "ACGTTGCAACGGTCA";
+
+    #[test]
+    fn side_filter() {
+        let left = segment(99, ClipSide::Left, SEQ, 30);
+        let right = segment(99, ClipSide::Right, SEQ, 30);
+        assert!(policy().accepts(&left) && policy().accepts(&right));
+        let policy = SplitReadPolicy {
+            side: Some(ClipSide::Right),
+            ..policy()
+        };
+        assert!(!policy.accepts(&left));
+        assert!(policy.accepts(&right));
+    }
+
+    #[test]
+    fn short_clips_are_dropped() {
+        assert!(policy().accepts(&segment(99, ClipSide::Left, &SEQ[..10], 30)));
+        assert!(!policy().accepts(&segment(99, ClipSide::Left, &SEQ[..9], 30)));
+    }
+
+    #[test]
+    fn mean_quality() {
+        let mut record = segment(99, ClipSide::Left, SEQ, 20);
+        assert!(policy().accepts(&record));
+        record.qual[0] = b'!';
+        assert!(!policy().accepts(&record));
+        record.qual.clear();
+        assert!(policy().accepts(&record));
+    }
+
+    #[test]
+    fn low_complexity_clips_are_dropped() {
+        assert!(!policy().accepts(&segment(99, ClipSide::Right, "AAAAAAAAAAAAAAA", 30)));
+        assert!(!policy().accepts(&segment(99, ClipSide::Right, "aaaaaaaaaaaaaGT", 30)));
+        // 12 of 15 bases is exactly the limit.
+        assert!(policy().accepts(&segment(99, ClipSide::Right, "AAAAAAAAAAAACGT", 30)));
+        let relaxed = SplitReadPolicy {
+            max_base_fraction: 1.0,
+            ..policy()
+        };
+        assert!(relaxed.accepts(&segment(99, ClipSide::Right, "AAAAAAAAAAAAAAA", 30)));
+    }
+
+    #[test]
+    fn proper_pairs() {
+        let strict = SplitReadPolicy {
+            proper_pair: true,
+            ..policy()
+        };
+        assert!(strict.accepts(&segment(99, ClipSide::Left, SEQ, 30)));
+        assert!(!strict.accepts(&segment(97, ClipSide::Left, SEQ, 30)));
+        assert!(policy().accepts(&segment(97, ClipSide::Left, SEQ, 30)));
+    }
+
+    #[test]
+    fn other_reads_are_never_accepted() {
+        let mut record = segment(99, ClipSide::Left, SEQ, 30);
+        record.name = "r1/1".to_string();
+        assert!(!policy().accepts(&record));
+    }
+
+    #[test]
+    fn primary_te_alignments_become_breakpoints() {
+        let sam = "@HD\tVN:1.6\n@SQ\tSN:HERVK\tLN:9000\n@SQ\tSN:L1HS\tLN:6000
+soft|r1|99|chr1|1000|L|30S70M\t16\tHERVK\t200\t60\t30M\t*\t0\t0\t*\t*
+soft|r1|99|chr1|1000|L|30S70M\t256\tL1HS\t10\t0\t30M\t*\t0\t0\t*\t*
+soft|r2|83|chr1|1069|R|70M30S\t0\tL1HS\t5000\t60\t30M\t*\t0\t0\t*\t*
+soft|r3|99|chr1|1000|L|30S70M\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*
+";
+        let reader = SamReader::new(sam.as_bytes()).unwrap();
+        let header = reader.header().clone();
+        let breakpoints = transfer_soft_clips(reader, &header).unwrap();
+        let summary: Vec<_> = breakpoints
+            .iter()
+            .map(|bp| {
+                (
+                    bp.read_id.as_str(),
+                    bp.position,
+                    bp.strand,
+                    bp.flank,
+                    bp.te_name.as_str(),
+                    bp.te_position,
+                    bp.te_strand,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "r1",
+                    1000,
+                    Strand::Forward,
+                    Flank::Right,
+                    "HERVK",
+                    200,
+                    Strand::Reverse
+                ),
+                (
+                    "r2",
+                    1069,
+                    Strand::Reverse,
+                    Flank::Left,
+                    "L1HS",
+                    5000,
+                    Strand::Forward
+                ),
+            ]
+        );
+        assert!(breakpoints.iter().all(|bp| bp.evidence == Evidence::Split));
+    }
+
+    #[test]
+    fn foreign_reads_are_an_error() {
+        let sam = "@SQ\tSN:HERVK\tLN:9000\nr1\t0\tHERVK\t200\t60\t30M\t*\t0\t0\t*\t*\n";
+        let reader = SamReader::new(sam.as_bytes()).unwrap();
+        let header = reader.header().clone();
+        let err = transfer_soft_clips(reader, &header).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unexpected read 'r1' in soft-clip alignments"
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Typed SAM optional fields (`TAG:TYPE:VALUE`) and their binary BAM encoding.
+
+use crate::bgzf::invalid_data;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuxValue {
+    /// `A`: a single printable character.
+    Char(u8),
+    /// `i`: any of the BAM integer types `cCsSiI`.
+    Int(i64),
+    /// `f`: single-precision float.
+    Float(f32),
+    /// `Z`: printable string.
+    String(String),
+    /// `H`: hex-encoded byte array, kept as its text form.
+    Hex(String),
+    /// `B` with an integer subtype, which is kept so the field round-trips.
+    IntArray(u8, Vec<i64>),
+    /// `B:f`.
+    FloatArray(Vec<f32>),
+}
+
+impl AuxValue {
+    /// The value as an `i32`, for integer fields that fit.
+    pub fn as_i32(&self) -> Option<i32> {
+        match self {
+            AuxValue::Int(v) => i32::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxField {
+    pub tag: [u8; 2],
+    pub value: AuxValue,
+}
+
+impl AuxField {
+    /// Appends the binary BAM form of the field to `aux`.
+    pub fn encode(&self, aux: &mut Vec<u8>) {
+        aux.extend_from_slice(&self.tag);
+        match &self.value {
+            AuxValue::Char(c) => aux.extend_from_slice(&[b'A', *c]),
+            AuxValue::Int(v) => {
+                if let Ok(v) = i32::try_from(*v) {
+                    aux.push(b'i');
+                    aux.extend_from_slice(&v.to_le_bytes());
+                } else {
+                    aux.push(b'I');
+                    aux.extend_from_slice(&(*v as u32).to_le_bytes());
+                }
+            }
+            AuxValue::Float(v) => {
+                aux.push(b'f');
+                aux.extend_from_slice(&v.to_le_bytes());
+            }
+            AuxValue::String(s) => {
+                aux.push(b'Z');
+                aux.extend_from_slice(s.as_bytes());
+                aux.push(0);
+            }
+            AuxValue::Hex(s) => {
+                aux.push(b'H');
+                aux.extend_from_slice(s.as_bytes());
+                aux.push(0);
+            }
+            AuxValue::IntArray(subtype, values) => {
+                aux.extend_from_slice(&[b'B', *subtype]);
+                aux.extend_from_slice(&(values.len() as u32).to_le_bytes());
+                for &v in values {
+                    aux.extend_from_slice(&v.to_le_bytes()[..type_size(*subtype)]);
+                }
+            }
+            AuxValue::FloatArray(values) => {
+                aux.extend_from_slice(b"Bf");
+                aux.extend_from_slice(&(values.len() as u32).to_le_bytes());
+                for v in values {
+                    aux.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for AuxField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = String::from_utf8_lossy(&self.tag);
+        match &self.value {
+            AuxValue::Char(c) => write!(f, "{}:A:{}", tag, *c as char),
+            AuxValue::Int(v) => write!(f, "{}:i:{}", tag, v),
+            AuxValue::Float(v) => write!(f, "{}:f:{}", tag, v),
+            AuxValue::String(s) => write!(f, "{}:Z:{}", tag, s),
+            AuxValue::Hex(s) => write!(f, "{}:H:{}", tag, s),
+            AuxValue::IntArray(subtype, values) => {
+                write!(f, "{}:B:{}", tag, *subtype as char)?;
+                values.iter().try_for_each(|v| write!(f, ",{}", v))
+            }
+            AuxValue::FloatArray(values) => {
+                write!(f, "{}:B:f", tag)?;
+                values.iter().try_for_each(|v| write!(f, ",{}", v))
+            }
+        }
+    }
+}
+
+impl FromStr for AuxField {
+    type Err = io::Error;
+
+    fn from_str(field: &str) -> io::Result<Self> {
+        let bad = || invalid_data(&format!("invalid SAM tag '{}'", field));
+        let mut parts = field.splitn(3, ':');
+        let (Some(tag), Some(kind), Some(value)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(bad());
+        };
+        let tag: [u8; 2] = tag.as_bytes().try_into().map_err(|_| bad())?;
+        let value = match kind {
+            "A" => AuxValue::Char(*value.as_bytes().first().ok_or_else(bad)?),
+            "i" => {
+                let v: i64 = value.parse().map_err(|_| bad())?;
+                if v < i64::from(i32::MIN) || v > i64::from(u32::MAX) {
+                    return Err(bad());
+                }
+                AuxValue::Int(v)
+            }
+            "f" => AuxValue::Float(value.parse().map_err(|_| bad())?),
+            "Z" => AuxValue::String(value.to_string()),
+            "H" => AuxValue::Hex(value.to_string()),
+            "B" => {
+                let mut values = value.split(',');
+                let subtype = values.next().ok_or_else(bad)?;
+                match subtype {
+                    "f" => AuxValue::FloatArray(
+                        values
+                            .map(|v| v.parse().map_err(|_| bad()))
+                            .collect::<io::Result<_>>()?,
+                    ),
+                    "c" | "C" | "s" | "S" | "i" | "I" => {
+                        let subtype = subtype.as_bytes()[0];
+                        let (min, max) = int_range(subtype);
+                        let values = values
+                            .map(|v| match v.parse::<i64>() {
+                                Ok(v) if (min..=max).contains(&v) => Ok(v),
+                                _ => Err(bad()),
+                            })
+                            .collect::<io::Result<_>>()?;
+                        AuxValue::IntArray(subtype, values)
+                    }
+                    _ => return Err(bad()),
+                }
+            }
+            _ => return Err(bad()),
+        };
+        Ok(AuxField { tag, value })
+    }
+}
+
+/// Iterates over the binary optional fields of a BAM record.
+pub struct AuxFields<'a> {
+    aux: &'a [u8],
+}
+
+/// The fields encoded in `aux`, in record order.
+pub fn fields(aux: &[u8]) -> AuxFields<'_> {
+    AuxFields { aux }
+}
+
+impl<'a> AuxFields<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.aux.len() < n {
+            self.aux = &[];
+            return Err(invalid_data("truncated optional field"));
+        }
+        let (head, tail) = self.aux.split_at(n);
+        self.aux = tail;
+        Ok(head)
+    }
+
+    fn read_field(&mut self) -> io::Result<AuxField> {
+        let head = self.take(3)?;
+        let tag = [head[0], head[1]];
+        let kind = head[2];
+        let value = match kind {
+            b'A' => AuxValue::Char(self.take(1)?[0]),
+            b'c' | b'C' | b's' | b'S' | b'i' | b'I' => {
+                AuxValue::Int(int_value(kind, self.take(type_size(kind))?))
+            }
+            b'f' => AuxValue::Float(float_value(self.take(4)?)),
+            b'Z' | b'H' => {
+                let end = self
+                    .aux
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| invalid_data("unterminated string field"))?;
+                let text = String::from_utf8_lossy(&self.take(end + 1)?[..end]).into_owned();
+                if kind == b'Z' {
+                    AuxValue::String(text)
+                } else {
+                    AuxValue::Hex(text)
+                }
+            }
+            b'B' => {
+                let head = self.take(5)?;
+                let subtype = head[0];
+                let count = u32::from_le_bytes([head[1], head[2], head[3], head[4]]) as usize;
+                match subtype {
+                    b'f' => AuxValue::FloatArray(
+                        (0..count)
+                            .map(|_| self.take(4).map(float_value))
+                            .collect::<io::Result<_>>()?,
+                    ),
+                    b'c' | b'C' | b's' | b'S' | b'i' | b'I' => AuxValue::IntArray(
+                        subtype,
+                        (0..count)
+                            .map(|_| self.take(type_size(subtype)).map(|b| int_value(subtype, b)))
+                            .collect::<io::Result<_>>()?,
+                    ),
+                    _ => return Err(invalid_data("unknown optional array type")),
+                }
+            }
+            _ => return Err(invalid_data("unknown optional field type")),
+        };
+        Ok(AuxField { tag, value })
+    }
+}
+
+impl Iterator for AuxFields<'_> {
+    type Item = io::Result<AuxField>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.aux.is_empty() {
+            return None;
+        }
+        let field = self.read_field();
+        if field.is_err() {
+            self.aux = &[];
+        }
+        Some(field)
+    }
+}
+
+fn type_size(kind: u8) -> usize {
+    match kind {
+        b'c' | b'C' | b'A' => 1,
+        b's' | b'S' => 2,
+        _ => 4,
+    }
+}
+
+fn int_range(kind: u8) -> (i64, i64) {
+    match kind {
+        b'c' => (i64::from(i8::MIN), i64::from(i8::MAX)),
+        b'C' => (0, i64::from(u8::MAX)),
+        b's' => (i64::from(i16::MIN), i64::from(i16::MAX)),
+        b'S' => (0, i64::from(u16::MAX)),
+        b'i' => (i64::from(i32::MIN), i64::from(i32::MAX)),
+        _ => (0, i64::from(u32::MAX)),
+    }
+}
+
+fn int_value(kind: u8, b: &[u8]) -> i64 {
+    match kind {
+        b'c' => i64::from(b[0] as i8),
+        b'C' => i64::from(b[0]),
+        b's' => i64::from(i16::from_le_bytes([b[0], b[1]])),
+        b'S' => i64::from(u16::from_le_bytes([b[0], b[1]])),
+        b'i' => i64::from(i32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        _ => i64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+    }
+}
+
+fn float_value(b: &[u8]) -> f32 {
+    f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
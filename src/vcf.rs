@@ -0,0 +1,314 @@
+//! VCF 4.2 output of TE insertion calls as symbolic `<INS:ME:...>` alleles.
+
+use crate::calling::Insertion;
+use crate::fasta::IndexedFasta;
+use crate::genotype::Genotype;
+use crate::population::{self, SiteStats};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Write;
+
+const META_INFO: &str = "\
+##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">
+##INFO=<ID=IMPRECISE,Number=0,Type=Flag,Description=\"No split reads resolve the exact breakpoints\">
+##INFO=<ID=TE,Number=1,Type=String,Description=\"TE family of the inserted element\">
+##INFO=<ID=STRAND,Number=1,Type=String,Description=\"Orientation of the inserted element\">
+##INFO=<ID=TSD,Number=1,Type=Integer,Description=\"Length of the target site duplication\">
+##INFO=<ID=BPL,Number=1,Type=Integer,Description=\"Last reference base before the insertion\">
+##INFO=<ID=BPR,Number=1,Type=Integer,Description=\"First reference base after the insertion\">
+##INFO=<ID=CHIMERIC,Number=1,Type=Integer,Description=\"Number of chimeric read pairs supporting the insertion\">
+##INFO=<ID=SPLIT,Number=1,Type=Integer,Description=\"Number of split reads supporting the insertion\">
+##INFO=<ID=AC,Number=A,Type=Integer,Description=\"Insertion alleles in called genotypes\">
+##INFO=<ID=AN,Number=1,Type=Integer,Description=\"Total alleles in called genotypes\">
+##INFO=<ID=AF,Number=A,Type=Float,Description=\"Insertion allele frequency among called genotypes\">
+##INFO=<ID=CR,Number=1,Type=Float,Description=\"Fraction of samples with a genotype call\">
+##INFO=<ID=HWE,Number=1,Type=Float,Description=\"Hardy-Weinberg equilibrium exact test p-value\">
+##ALT=<ID=INS:ME:ERV,Description=\"Insertion of an endogenous retrovirus\">
+##ALT=<ID=INS:ME:ALU,Description=\"Insertion of an ALU element\">
+##ALT=<ID=INS:ME:LINE1,Description=\"Insertion of a LINE1 element\">
+##ALT=<ID=INS:ME:SVA,Description=\"Insertion of an SVA element\">
+##ALT=<ID=INS:ME,Description=\"Insertion of another mobile element\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+##FORMAT=<ID=GQ,Number=1,Type=Integer,Description=\"Genotype quality\">
+##FORMAT=<ID=AD,Number=R,Type=Integer,Description=\"Reads supporting the reference and the insertion\">
+##FORMAT=<ID=PL,Number=G,Type=Integer,Description=\"Phred-scaled genotype likelihoods\">
+";
+
+/// Symbolic ALT allele for a TE family name.
+pub fn symbolic_allele(te_family: &str) -> &'static str {
+    let family = te_family.to_ascii_uppercase();
+    if family.contains("ALU") {
+        "<INS:ME:ALU>"
+    } else if family.starts_with("L1") || family.contains("LINE") {
+        "<INS:ME:LINE1>"
+    } else if family.contains("SVA") {
+        "<INS:ME:SVA>"
+    } else if family.contains("ERV") || family.contains("LTR") || family.starts_with("MER") {
+        "<INS:ME:ERV>"
+    } else {
+        "<INS:ME>"
+    }
+}
+
+/// Orders insertions like the reference contigs, keeping unknown contigs last.
+pub fn sort_by_contig(insertions: &mut [Insertion], contigs: &[(String, u64)]) {
+    insertions.sort_by_key(contig_order(contigs));
+}
+
+/// The sort key of [`sort_by_contig`]: contig rank, then position.
+pub fn contig_order(contigs: &[(String, u64)]) -> impl Fn(&Insertion) -> (usize, u32) + '_ {
+    let rank: HashMap<&str, usize> = contigs
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (name.as_str(), i))
+        .collect();
+    move |insertion| {
+        (
+            rank.get(insertion.chrom.as_str())
+                .copied()
+                .unwrap_or(usize::MAX),
+            insertion.position,
+        )
+    }
+}
+
+pub struct VcfWriter<W: Write> {
+    out: W,
+    reference: Option<IndexedFasta>,
+}
+
+impl<W: Write> VcfWriter<W> {
+    /// Writes the VCF header. Without an indexed reference, REF bases are written
+    /// as `N` and no contig lines are emitted.
+    pub fn new(
+        mut out: W,
+        reference: Option<IndexedFasta>,
+        reference_path: &str,
+        samples: &[&str],
+    ) -> Result<Self> {
+        writeln!(out, "##fileformat=VCFv4.2")?;
+        writeln!(out, "##source=ERVcaller-rs {}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(out, "##reference={}", reference_path)?;
+        if let Some(reference) = &reference {
+            for (name, length) in reference.contigs() {
+                writeln!(out, "##contig=<ID={},length={}>", name, length)?;
+            }
+        }
+        out.write_all(META_INFO.as_bytes())?;
+        write!(out, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT")?;
+        for sample in samples {
+            write!(out, "\t{}", sample)?;
+        }
+        writeln!(out)?;
+        Ok(VcfWriter { out, reference })
+    }
+
+    /// Writes one insertion with a genotype column per sample, in header order.
+    pub fn write_insertion(
+        &mut self,
+        insertion: &Insertion,
+        genotypes: &[Option<&Genotype>],
+    ) -> Result<()> {
+        let ref_base = match &mut self.reference {
+            Some(reference) => reference.base(&insertion.chrom, u64::from(insertion.position))?,
+            None => None,
+        };
+
+        let mut info = format!(
+            "SVTYPE=INS;TE={};STRAND={}",
+            insertion.te_family, insertion.strand
+        );
+        if insertion.left_breakpoint.is_none() && insertion.right_breakpoint.is_none() {
+            info.push_str(";IMPRECISE");
+        }
+        if let Some(tsd) = insertion.tsd_len {
+            info.push_str(&format!(";TSD={}", tsd));
+        }
+        if let Some(bp) = insertion.left_breakpoint {
+            info.push_str(&format!(";BPL={}", bp));
+        }
+        if let Some(bp) = insertion.right_breakpoint {
+            info.push_str(&format!(";BPR={}", bp));
+        }
+        info.push_str(&format!(
+            ";CHIMERIC={};SPLIT={}",
+            insertion.chimeric_reads, insertion.split_reads
+        ));
+        let stats = SiteStats::from_genotypes(genotypes);
+        if let (Some(af), Some(hwe)) = (stats.allele_frequency(), stats.hwe_p()) {
+            info.push_str(&format!(
+                ";AC={};AN={};AF={:.4};CR={:.4};HWE={}",
+                stats.allele_count(),
+                stats.allele_number(),
+                af,
+                stats.call_rate(),
+                population::format_probability(hwe)
+            ));
+        }
+
+        write!(
+            self.out,
+            "{}\t{}\t.\t{}\t{}\t.\tPASS\t{}\tGT:GQ:AD:PL",
+            insertion.chrom,
+            insertion.position,
+            ref_base.unwrap_or(b'N') as char,
+            symbolic_allele(&insertion.te_family),
+            info
+        )?;
+        for genotype in genotypes {
+            match genotype {
+                Some(g) => write!(
+                    self.out,
+                    "\t{}:{}:{},{}:{},{},{}",
+                    g.call, g.gq, g.ref_reads, g.alt_reads, g.pl[0], g.pl[1], g.pl[2]
+                )?,
+                None => write!(self.out, "\t./.:.:.:.")?,
+            }
+        }
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breakpoint::Strand;
+    use crate::genotype::Call;
+    use crate::testdir::TempDir;
+
+    fn insertion(chrom: &str, position: u32) -> Insertion {
+        Insertion {
+            chrom: chrom.to_string(),
+            position,
+            left_breakpoint: None,
+            right_breakpoint: None,
+            te_family: "HERVK".to_string(),
+            strand: Strand::Forward,
+            tsd_len: None,
+            chimeric_reads: 4,
+            split_reads: 0,
+            genotype: None,
+        }
+    }
+
+    fn genotype(call: Call, ref_reads: u32, alt_reads: u32) -> Genotype {
+        Genotype {
+            call,
+            ref_reads,
+            alt_reads,
+            pl: [30, 0, 40],
+            gq: 30,
+        }
+    }
+
+    /// The VCF lines written for `insertions`, each with the same `genotypes`.
+    fn vcf(
+        reference: Option<IndexedFasta>,
+        insertions: &[Insertion],
+        genotypes: &[Option<&Genotype>],
+    ) -> Vec<String> {
+        let samples: Vec<String> = (1..=genotypes.len()).map(|i| format!("s{}", i)).collect();
+        let samples: Vec<&str> = samples.iter().map(String::as_str).collect();
+        let mut writer = VcfWriter::new(Vec::new(), reference, "hg38.fa", &samples).unwrap();
+        for insertion in insertions {
+            writer.write_insertion(insertion, genotypes).unwrap();
+        }
+        let out = writer.finish().unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn families_map_to_symbolic_alleles() {
+        for (family, allele) in [
+            ("AluYa5", "<INS:ME:ALU>"),
+            ("L1HS", "<INS:ME:LINE1>"),
+            ("LINE1_Ta", "<INS:ME:LINE1>"),
+            ("SVA_E", "<INS:ME:SVA>"),
+            ("HERVK", "<INS:ME:ERV>"),
+            ("LTR5_Hs", "<INS:ME:ERV>"),
+            ("MER41B", "<INS:ME:ERV>"),
+            ("HSATII", "<INS:ME>"),
+        ] {
+            assert_eq!(symbolic_allele(family), allele, "{}", family);
+        }
+    }
+
+    #[test]
+    fn header_without_reference() {
+        let lines = vcf(None, &[], &[None, None]);
+        assert_eq!(lines[0], "##fileformat=VCFv4.2");
+        assert_eq!(lines[2], "##reference=hg38.fa");
+        assert!(!lines.iter().any(|line| line.starts_with("##contig")));
+        assert_eq!(
+            lines.last().unwrap(),
+            "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2"
+        );
+    }
+
+    #[test]
+    fn imprecise_site_without_fai_has_n_ref_and_missing_genotypes() {
+        let lines = vcf(None, &[insertion("chr1", 5)], &[None]);
+        assert_eq!(
+            lines.last().unwrap(),
+            "chr1\t5\t.\tN\t<INS:ME:ERV>\t.\tPASS\tSVTYPE=INS;TE=HERVK;STRAND=+;IMPRECISE;CHIMERIC=4;SPLIT=0\tGT:GQ:AD:PL\t./.:.:.:."
+        );
+    }
+
+    #[test]
+    fn precise_site_with_reference_and_genotypes() {
+        let dir = TempDir::with_files(
+            "vcf",
+            &[
+                ("ref.fa", ">chr1\nACGTACGTAC\nGGGG\n"),
+                ("ref.fa.fai", "chr1\t14\t6\t10\t11\n"),
+            ],
+        );
+        let reference = IndexedFasta::open(dir.join("ref.fa")).unwrap();
+        let site = Insertion {
+            left_breakpoint: Some(12),
+            right_breakpoint: Some(10),
+            tsd_len: Some(3),
+            split_reads: 2,
+            ..insertion("chr1", 12)
+        };
+        let het = genotype(Call::Het, 5, 6);
+        let lines = vcf(Some(reference), &[site], &[Some(&het), None]);
+        assert!(lines.contains(&"##contig=<ID=chr1,length=14>".to_string()));
+        assert_eq!(
+            lines.last().unwrap(),
+            "chr1\t12\t.\tG\t<INS:ME:ERV>\t.\tPASS\tSVTYPE=INS;TE=HERVK;STRAND=+;TSD=3;BPL=12;BPR=10;CHIMERIC=4;SPLIT=2;AC=1;AN=2;AF=0.5000;CR=0.5000;HWE=1.0000\tGT:GQ:AD:PL\t0/1:30:5,6:30,0,40\t./.:.:.:."
+        );
+    }
+
+    #[test]
+    fn sites_follow_the_contig_order() {
+        let mut insertions = vec![
+            insertion("chrUn", 1),
+            insertion("chr1", 300),
+            insertion("chr2", 10),
+            insertion("chr1", 20),
+        ];
+        sort_by_contig(
+            &mut insertions,
+            &[("chr1".to_string(), 1000), ("chr2".to_string(), 1000)],
+        );
+        let order: Vec<(&str, u32)> = insertions
+            .iter()
+            .map(|i| (i.chrom.as_str(), i.position))
+            .collect();
+        assert_eq!(
+            order,
+            [("chr1", 20), ("chr1", 300), ("chr2", 10), ("chrUn", 1)]
+        );
+    }
+}